@@ -0,0 +1,151 @@
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+use control::{CircuitError, CircuitId, CircuitPurpose, Controller, Error, ReadTimeout};
+use events::{CircEvent, CircStatus};
+
+// Builds a circuit through a chosen list of relays and waits until tor reports it as built.
+pub struct CircuitBuilder {
+    path: Vec<String>,
+    purpose: Option<CircuitPurpose>,
+    timeout: Duration,
+}
+
+impl Default for CircuitBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CircuitBuilder {
+    pub fn new() -> Self {
+        CircuitBuilder {
+            path: vec![],
+            purpose: None,
+            timeout: Duration::from_secs(60),
+        }
+    }
+
+    // Adds a relay, by fingerprint or nickname, at the end of the path.
+    pub fn relay(&mut self, relay: &str) -> &mut Self {
+        self.path.push(relay.to_string());
+        self
+    }
+
+    pub fn relays(&mut self, relays: &[&str]) -> &mut Self {
+        for relay in relays {
+            self.relay(relay);
+        }
+        self
+    }
+
+    pub fn purpose(&mut self, purpose: CircuitPurpose) -> &mut Self {
+        self.purpose = Some(purpose);
+        self
+    }
+
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    // Builds the circuit, returning its id once tor has built it.  A circuit that is not built
+    // before the timeout is closed.
+    pub fn build<T>(&self, controller: &mut Controller<T>) -> Result<CircuitId, Error>
+        where T: Read + Write + ReadTimeout
+    {
+        controller.add_events(&["CIRC"])?;
+        let res = self.build_circuit(controller);
+        controller.remove_events(&["CIRC"])?;
+        res
+    }
+
+    fn build_circuit<T>(&self, controller: &mut Controller<T>) -> Result<CircuitId, Error>
+        where T: Read + Write + ReadTimeout
+    {
+        let deadline = Instant::now() + self.timeout;
+        let path = self.path.iter().map(|relay| relay.as_str()).collect::<Vec<_>>();
+        let circ_id = controller.cmd_extendcircuit(None, &path, self.purpose)?;
+
+        loop {
+            let now = Instant::now();
+            let event = if now < deadline {
+                controller.wait_event_matching(deadline - now, |event| {
+                        event.keyword == "CIRC" &&
+                        event.args().split(' ').nth(0) == Some(circ_id.as_ref())
+                    })?
+            } else {
+                None
+            };
+            let circ = match event {
+                Some(event) => CircEvent::parse(&event)?,
+                None => {
+                    controller.cmd_closecircuit(&circ_id, false).unwrap_or(());
+                    return Err(Error::Circuit(CircuitError::Timeout));
+                }
+            };
+            match circ.status {
+                CircStatus::Built => return Ok(circ_id),
+                CircStatus::Failed | CircStatus::Closed => {
+                    return Err(Error::Circuit(CircuitError::Failed(circ.reason)))
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use control::{CircuitError, CircuitId, CircuitReason, Error};
+    use control::test::mock_controller;
+    use super::CircuitBuilder;
+
+    #[test]
+    fn test_build_circuit() {
+        let (mut controller, stream) =
+            mock_controller(&["250 OK\r\n",
+                              "250 EXTENDED 5\r\n650 CIRC 4 BUILT\r\n650 CIRC 5 LAUNCHED\r\n\
+                               650 CIRC 5 BUILT $AAAA~relay1,$BBBB~relay2\r\n",
+                              "250 OK\r\n"]);
+        let circ_id = CircuitBuilder::new()
+            .relays(&["relay1", "relay2"])
+            .build(&mut controller)
+            .unwrap();
+        assert_eq!(circ_id, CircuitId::from("5"));
+        assert_eq!(stream.sent(),
+                   ["SETEVENTS CIRC", "EXTENDCIRCUIT 0 relay1,relay2", "SETEVENTS"]);
+    }
+
+    #[test]
+    fn test_build_circuit_failed() {
+        let (mut controller, _) =
+            mock_controller(&["250 OK\r\n",
+                              "250 EXTENDED 5\r\n650 CIRC 5 LAUNCHED\r\n\
+                               650 CIRC 5 FAILED REASON=TIMEOUT\r\n",
+                              "250 OK\r\n"]);
+        match CircuitBuilder::new().relay("relay1").build(&mut controller) {
+            Err(Error::Circuit(CircuitError::Failed(Some(CircuitReason::Timeout)))) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_build_circuit_timeout() {
+        let (mut controller, stream) =
+            mock_controller(&["250 OK\r\n",
+                              "250 EXTENDED 5\r\n650 CIRC 5 LAUNCHED\r\n",
+                              "250 OK\r\n",
+                              "250 OK\r\n"]);
+        match CircuitBuilder::new()
+            .relay("relay1")
+            .timeout(Duration::from_millis(10))
+            .build(&mut controller) {
+            Err(Error::Circuit(CircuitError::Timeout)) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert_eq!(stream.sent(),
+                   ["SETEVENTS CIRC", "EXTENDCIRCUIT 0 relay1", "CLOSECIRCUIT 5", "SETEVENTS"]);
+    }
+}
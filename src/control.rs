@@ -1,5 +1,5 @@
 #![forbid(unsafe_code)]
-use std::net::ToSocketAddrs;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::num;
 use std::net::{IpAddr, TcpStream, Shutdown};
 use std::io;
@@ -7,11 +7,15 @@ use std::io::{Read, Write};
 // use std::str;
 use std::io::{BufReader, BufRead, BufWriter};
 use std::option::Option;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::fs::File;
+use std::path::PathBuf;
+use std::error;
 use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use regex::Regex;
 use hex::FromHex;
 use crypto::hmac::Hmac;
 use crypto::sha2::Sha256;
@@ -19,6 +23,9 @@ use crypto::mac::Mac;
 use crypto::util::fixed_time_eq;
 use rand::Rng;
 
+use onion::{base32_encode, base64_decode, base64_encode, strip_onion_suffix, OnionAddress};
+use events::{AddrMapEvent, HsDescAction, HsDescContentEvent, HsDescEvent, HsDescReason};
+
 // Gives val from Some(val) or returns Err(Error::Reply($rep_err))
 // macro_rules! some_or_rep_err {
 //     ($expr:expr, $rep_err:expr) => (match $expr {
@@ -29,26 +36,6 @@ use rand::Rng;
 //     })
 // }
 
-// Gives the $re regex capture of $str or returns Reply(RegexCapture) error
-macro_rules! re_cap_or_err {
-    ($re:expr, $str:expr) => (match $re.captures($str) {
-        Some(val) => val,
-        None => {
-            return Err(Error::ParseReply(ParseReplyError::RegexCapture));
-        }
-    })
-}
-
-// Gives the $name found in $cap regex capture or returns Reply(MissingField) error
-macro_rules! cap_name_or_err {
-    ($cap:expr, $name:expr) => (match $cap.name($name) {
-        Some(val) => val.as_str(),
-        None => {
-            return Err(Error::ParseReply(ParseReplyError::MissingField));
-        }
-    })
-}
-
 // enum Auth {
 //    None,
 //    Cookie(Path),
@@ -60,7 +47,7 @@ macro_rules! cap_name_or_err {
 //    Unix(Path),
 // }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ReplyStatus {
     Positive,
     TempNegative,
@@ -69,10 +56,99 @@ pub enum ReplyStatus {
     Unknown,
 }
 
-#[derive(Debug)]
+// Status code of a reply, as listed in section 4 of the control spec.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ReplyCode {
+    Ok,                     // 250
+    OperationUnnecessary,   // 251
+    ResourceExhausted,      // 451
+    SyntaxError,            // 500
+    UnrecognizedCommand,    // 510
+    UnimplementedCommand,   // 511
+    SyntaxErrorInArgument,  // 512
+    UnrecognizedArgument,   // 513
+    AuthRequired,           // 514
+    BadAuth,                // 515
+    Unspecified,            // 550
+    InternalError,          // 551
+    UnrecognizedEntity,     // 552
+    InvalidConfigValue,     // 553
+    InvalidDescriptor,      // 554
+    UnmanagedEntity,        // 555
+    Async,                  // 650
+    Other(u16),
+}
+
+impl ReplyCode {
+    pub fn code(&self) -> u16 {
+        match *self {
+            ReplyCode::Ok => 250,
+            ReplyCode::OperationUnnecessary => 251,
+            ReplyCode::ResourceExhausted => 451,
+            ReplyCode::SyntaxError => 500,
+            ReplyCode::UnrecognizedCommand => 510,
+            ReplyCode::UnimplementedCommand => 511,
+            ReplyCode::SyntaxErrorInArgument => 512,
+            ReplyCode::UnrecognizedArgument => 513,
+            ReplyCode::AuthRequired => 514,
+            ReplyCode::BadAuth => 515,
+            ReplyCode::Unspecified => 550,
+            ReplyCode::InternalError => 551,
+            ReplyCode::UnrecognizedEntity => 552,
+            ReplyCode::InvalidConfigValue => 553,
+            ReplyCode::InvalidDescriptor => 554,
+            ReplyCode::UnmanagedEntity => 555,
+            ReplyCode::Async => 650,
+            ReplyCode::Other(code) => code,
+        }
+    }
+
+    pub fn status(&self) -> ReplyStatus {
+        match self.code() / 100 {
+            2 => ReplyStatus::Positive,
+            4 => ReplyStatus::TempNegative,
+            5 => ReplyStatus::PermNegative,
+            6 => ReplyStatus::Async,
+            _ => ReplyStatus::Unknown,
+        }
+    }
+}
+
+impl From<u16> for ReplyCode {
+    fn from(code: u16) -> Self {
+        match code {
+            250 => ReplyCode::Ok,
+            251 => ReplyCode::OperationUnnecessary,
+            451 => ReplyCode::ResourceExhausted,
+            500 => ReplyCode::SyntaxError,
+            510 => ReplyCode::UnrecognizedCommand,
+            511 => ReplyCode::UnimplementedCommand,
+            512 => ReplyCode::SyntaxErrorInArgument,
+            513 => ReplyCode::UnrecognizedArgument,
+            514 => ReplyCode::AuthRequired,
+            515 => ReplyCode::BadAuth,
+            550 => ReplyCode::Unspecified,
+            551 => ReplyCode::InternalError,
+            552 => ReplyCode::UnrecognizedEntity,
+            553 => ReplyCode::InvalidConfigValue,
+            554 => ReplyCode::InvalidDescriptor,
+            555 => ReplyCode::UnmanagedEntity,
+            650 => ReplyCode::Async,
+            code => ReplyCode::Other(code),
+        }
+    }
+}
+
+impl fmt::Display for ReplyCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.code())
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct ReplyLine {
-    reply: String,
-    data: Option<String>,
+    pub reply: String,
+    pub data: Option<String>,
 }
 
 #[derive(Debug)]
@@ -82,12 +158,404 @@ pub struct Reply {
     lines: Vec<ReplyLine>,
 }
 
-#[derive(Debug)]
+impl Reply {
+    // The n-th line of the reply, or an error if tor sent less lines.
+    fn line(&self, n: usize) -> Result<&ReplyLine, Error> {
+        self.lines.get(n).ok_or(Error::RawReply(RawReplyError::InvalidReply))
+    }
+}
+
+// An asynchronous event (650 reply) received from tor.  The keyword is the first word of the
+// first line, for example "CIRC" or "STATUS_GENERAL".
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub keyword: String,
+    pub lines: Vec<ReplyLine>,
+}
+
+impl Event {
+    fn from_lines(lines: Vec<ReplyLine>) -> Result<Event, Error> {
+        let keyword = match lines.first().and_then(|line| line.reply.split(' ').nth(0)) {
+            Some(keyword) => keyword.to_string(),
+            None => return Err(Error::RawReply(RawReplyError::InvalidReply)),
+        };
+        Ok(Event { keyword, lines })
+    }
+
+    // The first line of the event, without the keyword.
+    pub fn args(&self) -> &str {
+        self.lines
+            .first()
+            .and_then(|line| line.reply.get(self.keyword.len()..))
+            .unwrap_or("")
+            .trim_start_matches(' ')
+    }
+}
+
+// Arguments of a reply line or an event: positional arguments followed by "Key=Value" pairs, as
+// in "CIRC 7 BUILT PURPOSE=GENERAL" or "VERSION Tor=\"0.4.8.9\"".  Quoted arguments and values
+// are unescaped.  Positional arguments found after the first keyword are ignored.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReplyArgs {
+    positional: Vec<String>,
+    keywords: Vec<(String, String)>,
+}
+
+impl ReplyArgs {
+    pub fn parse(line: &str) -> Result<ReplyArgs, Error> {
+        let mut args = ReplyArgs::default();
+        let mut rest = line.trim_start_matches(' ');
+        while !rest.is_empty() {
+            let token_end = rest.find(' ').unwrap_or(rest.len());
+            let keyword = rest[..token_end]
+                .find('=')
+                .filter(|&eq| is_keyword(&rest[..eq]));
+            let after = match keyword {
+                Some(eq) => {
+                    let (value, after) = read_token(&rest[eq + 1..])?;
+                    args.keywords.push((rest[..eq].to_string(), value));
+                    after
+                }
+                None => {
+                    let (value, after) = read_token(rest)?;
+                    if args.keywords.is_empty() {
+                        args.positional.push(value);
+                    }
+                    after
+                }
+            };
+            if !after.is_empty() && !after.starts_with(' ') {
+                return Err(Error::ParseReply(ParseReplyError::InvalidQuotedString));
+            }
+            rest = after.trim_start_matches(' ');
+        }
+        Ok(args)
+    }
+
+    pub fn positional(&self) -> &[String] {
+        &self.positional
+    }
+
+    pub fn arg(&self, n: usize) -> Option<&str> {
+        self.positional.get(n).map(|arg| arg.as_str())
+    }
+
+    pub fn keywords(&self) -> &[(String, String)] {
+        &self.keywords
+    }
+
+    // The value of the first key, if given.
+    pub fn value(&self, key: &str) -> Option<&str> {
+        self.keywords.iter().find(|&(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    // The values of a key that can be given several times.
+    pub fn values(&self, key: &str) -> Vec<&str> {
+        self.keywords.iter().filter(|&(k, _)| k == key).map(|(_, v)| v.as_str()).collect()
+    }
+
+    // Parses the value of a key, failing if it is missing.
+    pub fn get<V: FromStr>(&self, key: &str) -> Result<V, Error> {
+        self.get_opt(key)?.ok_or(Error::ParseReply(ParseReplyError::KeyNotFound))
+    }
+
+    // Splits a "Key=Value" line of a GETCONF or GETINFO reply.  Unlike keyword arguments, the
+    // value runs to the end of the line; it is unescaped if it is a QuotedString, and None for a
+    // bare "Key".
+    pub fn key_value(line: &str) -> Result<(String, Option<String>), Error> {
+        match line.find('=') {
+            Some(eq) => Ok((line[..eq].to_string(), Some(unquote(&line[eq + 1..])?))),
+            None => Ok((line.to_string(), None)),
+        }
+    }
+
+    // Parses the value of a key, if given.
+    pub fn get_opt<V: FromStr>(&self, key: &str) -> Result<Option<V>, Error> {
+        match self.value(key) {
+            Some(value) => {
+                let invalid = || Error::ParseReply(ParseReplyError::InvalidValue(key.to_string()));
+                Ok(Some(value.parse::<V>().map_err(|_| invalid())?))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+fn is_keyword(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Reads a QuotedString or a word at the start of s, returning it along with the rest of s.
+fn read_token(s: &str) -> Result<(String, &str), Error> {
+    if s.starts_with('"') {
+        read_quoted(s)
+    } else {
+        let end = s.find(' ').unwrap_or(s.len());
+        Ok((s[..end].to_string(), &s[end..]))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Signal {
+    Reload,
+    Shutdown,
+    Dump,
+    Debug,
+    Halt,
+    ClearDnsCache,
+    Newnym,
+    Heartbeat,
+    Dormant,
+    Active,
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Signal::Reload => write!(f, "RELOAD"),
+            Signal::Shutdown => write!(f, "SHUTDOWN"),
+            Signal::Dump => write!(f, "DUMP"),
+            Signal::Debug => write!(f, "DEBUG"),
+            Signal::Halt => write!(f, "HALT"),
+            Signal::ClearDnsCache => write!(f, "CLEARDNSCACHE"),
+            Signal::Newnym => write!(f, "NEWNYM"),
+            Signal::Heartbeat => write!(f, "HEARTBEAT"),
+            Signal::Dormant => write!(f, "DORMANT"),
+            Signal::Active => write!(f, "ACTIVE"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CircuitId(String);
+
+impl<T: Into<String>> From<T> for CircuitId {
+    fn from(s: T) -> Self {
+        CircuitId(s.into())
+    }
+}
+
+impl AsRef<str> for CircuitId {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for CircuitId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct StreamId(String);
+
+impl<T: Into<String>> From<T> for StreamId {
+    fn from(s: T) -> Self {
+        StreamId(s.into())
+    }
+}
+
+impl AsRef<str> for StreamId {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// Reason for a circuit to fail or be closed, as reported in CIRC events.  Circuits closed with
+// CLOSECIRCUIT are reported with Requested.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CircuitReason {
+    None,
+    TorProtocol,
+    Internal,
+    Requested,
+    Hibernating,
+    ResourceLimit,
+    ConnectFailed,
+    OrIdentity,
+    ChannelClosed,
+    Finished,
+    Timeout,
+    Destroyed,
+    NoPath,
+    NoSuchService,
+    MeasurementExpired,
+    IpNowRedundant,
+    Unknown(String),
+}
+
+impl From<&str> for CircuitReason {
+    fn from(s: &str) -> Self {
+        match s {
+            "NONE" => CircuitReason::None,
+            "TORPROTOCOL" => CircuitReason::TorProtocol,
+            "INTERNAL" => CircuitReason::Internal,
+            "REQUESTED" => CircuitReason::Requested,
+            "HIBERNATING" => CircuitReason::Hibernating,
+            "RESOURCELIMIT" => CircuitReason::ResourceLimit,
+            "CONNECTFAILED" => CircuitReason::ConnectFailed,
+            "OR_IDENTITY" => CircuitReason::OrIdentity,
+            "CHANNEL_CLOSED" => CircuitReason::ChannelClosed,
+            "FINISHED" => CircuitReason::Finished,
+            "TIMEOUT" => CircuitReason::Timeout,
+            "DESTROYED" => CircuitReason::Destroyed,
+            "NOPATH" => CircuitReason::NoPath,
+            "NOSUCHSERVICE" => CircuitReason::NoSuchService,
+            "MEASUREMENT_EXPIRED" => CircuitReason::MeasurementExpired,
+            "IP_NOW_REDUNDANT" => CircuitReason::IpNowRedundant,
+            _ => CircuitReason::Unknown(s.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for CircuitReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CircuitReason::None => write!(f, "NONE"),
+            CircuitReason::TorProtocol => write!(f, "TORPROTOCOL"),
+            CircuitReason::Internal => write!(f, "INTERNAL"),
+            CircuitReason::Requested => write!(f, "REQUESTED"),
+            CircuitReason::Hibernating => write!(f, "HIBERNATING"),
+            CircuitReason::ResourceLimit => write!(f, "RESOURCELIMIT"),
+            CircuitReason::ConnectFailed => write!(f, "CONNECTFAILED"),
+            CircuitReason::OrIdentity => write!(f, "OR_IDENTITY"),
+            CircuitReason::ChannelClosed => write!(f, "CHANNEL_CLOSED"),
+            CircuitReason::Finished => write!(f, "FINISHED"),
+            CircuitReason::Timeout => write!(f, "TIMEOUT"),
+            CircuitReason::Destroyed => write!(f, "DESTROYED"),
+            CircuitReason::NoPath => write!(f, "NOPATH"),
+            CircuitReason::NoSuchService => write!(f, "NOSUCHSERVICE"),
+            CircuitReason::MeasurementExpired => write!(f, "MEASUREMENT_EXPIRED"),
+            CircuitReason::IpNowRedundant => write!(f, "IP_NOW_REDUNDANT"),
+            CircuitReason::Unknown(ref reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+// Reason for a stream to be closed.  Reasons are given as numbers in CLOSESTREAM, and as names in
+// STREAM events, where END and PRIVATE_ADDR can also appear.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamReason {
+    Misc,
+    ResolveFailed,
+    ConnectRefused,
+    ExitPolicy,
+    Destroy,
+    Done,
+    Timeout,
+    NoRoute,
+    Hibernating,
+    Internal,
+    ResourceLimit,
+    ConnReset,
+    TorProtocol,
+    NotDirectory,
+    End,
+    PrivateAddr,
+    Unknown(String),
+}
+
+impl StreamReason {
+    // Numeric code of the reason, as used by CLOSESTREAM.
+    pub fn code(&self) -> Option<u8> {
+        match *self {
+            StreamReason::Misc => Some(1),
+            StreamReason::ResolveFailed => Some(2),
+            StreamReason::ConnectRefused => Some(3),
+            StreamReason::ExitPolicy => Some(4),
+            StreamReason::Destroy => Some(5),
+            StreamReason::Done => Some(6),
+            StreamReason::Timeout => Some(7),
+            StreamReason::NoRoute => Some(8),
+            StreamReason::Hibernating => Some(9),
+            StreamReason::Internal => Some(10),
+            StreamReason::ResourceLimit => Some(11),
+            StreamReason::ConnReset => Some(12),
+            StreamReason::TorProtocol => Some(13),
+            StreamReason::NotDirectory => Some(14),
+            StreamReason::End | StreamReason::PrivateAddr | StreamReason::Unknown(_) => None,
+        }
+    }
+}
+
+impl From<&str> for StreamReason {
+    fn from(s: &str) -> Self {
+        match s {
+            "MISC" => StreamReason::Misc,
+            "RESOLVEFAILED" => StreamReason::ResolveFailed,
+            "CONNECTREFUSED" => StreamReason::ConnectRefused,
+            "EXITPOLICY" => StreamReason::ExitPolicy,
+            "DESTROY" => StreamReason::Destroy,
+            "DONE" => StreamReason::Done,
+            "TIMEOUT" => StreamReason::Timeout,
+            "NOROUTE" => StreamReason::NoRoute,
+            "HIBERNATING" => StreamReason::Hibernating,
+            "INTERNAL" => StreamReason::Internal,
+            "RESOURCELIMIT" => StreamReason::ResourceLimit,
+            "CONNRESET" => StreamReason::ConnReset,
+            "TORPROTOCOL" => StreamReason::TorProtocol,
+            "NOTDIRECTORY" => StreamReason::NotDirectory,
+            "END" => StreamReason::End,
+            "PRIVATE_ADDR" => StreamReason::PrivateAddr,
+            _ => StreamReason::Unknown(s.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for StreamReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StreamReason::Misc => write!(f, "MISC"),
+            StreamReason::ResolveFailed => write!(f, "RESOLVEFAILED"),
+            StreamReason::ConnectRefused => write!(f, "CONNECTREFUSED"),
+            StreamReason::ExitPolicy => write!(f, "EXITPOLICY"),
+            StreamReason::Destroy => write!(f, "DESTROY"),
+            StreamReason::Done => write!(f, "DONE"),
+            StreamReason::Timeout => write!(f, "TIMEOUT"),
+            StreamReason::NoRoute => write!(f, "NOROUTE"),
+            StreamReason::Hibernating => write!(f, "HIBERNATING"),
+            StreamReason::Internal => write!(f, "INTERNAL"),
+            StreamReason::ResourceLimit => write!(f, "RESOURCELIMIT"),
+            StreamReason::ConnReset => write!(f, "CONNRESET"),
+            StreamReason::TorProtocol => write!(f, "TORPROTOCOL"),
+            StreamReason::NotDirectory => write!(f, "NOTDIRECTORY"),
+            StreamReason::End => write!(f, "END"),
+            StreamReason::PrivateAddr => write!(f, "PRIVATE_ADDR"),
+            StreamReason::Unknown(ref reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CircuitPurpose {
+    General,
+    Controller,
+}
+
+impl fmt::Display for CircuitPurpose {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CircuitPurpose::General => write!(f, "general"),
+            CircuitPurpose::Controller => write!(f, "controller"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum AuthMethod {
     Null,
     HashedPassword,
     Cookie,
     SafeCookie,
+    // Method added by a newer tor
+    Unknown(String),
 }
 
 #[derive(Debug)]
@@ -98,45 +566,297 @@ pub struct ProtocolInfo {
     cookie_files: Vec<String>,
 }
 
+impl ProtocolInfo {
+    fn parse(reply: &Reply) -> Result<ProtocolInfo, Error> {
+        let prot_inf = ReplyArgs::parse(&reply.line(0)?.reply)?;
+        if prot_inf.arg(0) != Some("PROTOCOLINFO") {
+            return Err(Error::RawReply(RawReplyError::UnexpectedReply));
+        }
+        let version = prot_inf.arg(1)
+            .ok_or(Error::ParseReply(ParseReplyError::MissingField))?
+            .parse::<u8>()
+            .map_err(|err| Error::ParseReply(ParseReplyError::ParseIntError(err)))?;
+        if version != 1 {
+            return Err(Error::ParseReply(ParseReplyError::UnsupportedProtocolInfoVersion(version)));
+        }
+
+        let mut tor_version = String::new();
+        let mut cookie_files = Vec::new();
+        let mut auth_methods = Vec::new();
+
+        for line in reply.lines.iter().skip(1) {
+            let args = ReplyArgs::parse(&line.reply)?;
+            match args.arg(0) {
+                Some("AUTH") => {
+                    auth_methods = args.value("METHODS")
+                        .ok_or(Error::ParseReply(ParseReplyError::MissingField))?
+                        .split(',')
+                        .map(|x| match x {
+                            "NULL" => AuthMethod::Null,
+                            "HASHEDPASSWORD" => AuthMethod::HashedPassword,
+                            "COOKIE" => AuthMethod::Cookie,
+                            "SAFECOOKIE" => AuthMethod::SafeCookie,
+                            _ => AuthMethod::Unknown(x.to_string()),
+                        })
+                        .collect();
+                    for cookie_file in args.values("COOKIEFILE") {
+                        cookie_files.push(cookie_file.to_string());
+                    }
+                }
+                Some("VERSION") => {
+                    // The optional arguments that follow are not used so far
+                    tor_version = args.get("Tor")?;
+                }
+                Some("OK") => (), // End of PROTOCOLINFO reply
+                _ => (), // Unrecognized InfoLine
+            }
+        }
+        Ok(ProtocolInfo {
+            protocol_info_ver: version,
+            tor_ver: tor_version,
+            auth_methods: auth_methods,
+            cookie_files: cookie_files,
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct AuthChallenge {
     server_hash: [u8; 32],
     server_nonce: [u8; 32],
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct AddOnion {
     pub key: OnionKey,
     pub flags: Vec<OnionFlags>,
-    pub ports: Vec<(u16, Option<u16>)>,
+    // Maximum number of concurrent streams per rendezvous circuit, 0 for unlimited
+    pub max_streams: Option<u16>,
+    // Virtual port and where to forward it, the same port on localhost if None
+    pub ports: Vec<(u16, Option<OnionTarget>)>,
     pub client_auths: Vec<OnionClientAuth>,
+    pub client_auths_v3: Vec<OnionClientAuthV3>,
+    // Only v3 services support proof-of-work, the introduction rate limits can only be set for
+    // services configured with HiddenServiceDir
+    pub pow_defense: PowDefense,
 }
 
-impl fmt::Display for AddOnion {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "ADD_ONION ")?;
-        write!(f, "{}", self.key)?;
-        if !self.flags.is_empty() {
-            write!(f, " ")?;
-            write_join(f, &self.flags, ",")?;
+impl AddOnion {
+    // Checks the options that tor would reject, without asking tor.
+    pub fn validate(&self) -> Result<(), OnionError> {
+        if self.ports.is_empty() {
+            return Err(OnionError::NoPortGiven);
+        }
+        let v2_key = matches!(self.key, OnionKey::New(KeyType::Rsa1024) | OnionKey::Rsa1024(_));
+        // The authorization flags are needed if and only if there are clients
+        if self.flags.contains(&OnionFlags::BasicAuth) == self.client_auths.is_empty() {
+            return Err(OnionError::ClientAuthWithoutFlag(OnionFlags::BasicAuth));
+        }
+        if self.flags.contains(&OnionFlags::V3Auth) == self.client_auths_v3.is_empty() {
+            return Err(OnionError::ClientAuthWithoutFlag(OnionFlags::V3Auth));
+        }
+        if (self.flags.contains(&OnionFlags::BasicAuth) && !v2_key) ||
+           (self.flags.contains(&OnionFlags::V3Auth) && v2_key) {
+            return Err(OnionError::KeyTypeMismatch);
         }
-        for &(a, b) in &self.ports {
-            match b {
-                None => write!(f, " Port={}", a)?,
-                Some(n) => write!(f, " Port={},{}", a, n)?,
+        if self.pow_defense.enabled && v2_key {
+            return Err(OnionError::KeyTypeMismatch);
+        }
+        self.pow_defense.validate()?;
+        for &(_, ref target) in &self.ports {
+            if let Some(OnionTarget::Unix(ref path)) = *target {
+                // Tor splits the Port value at the first comma, even in a quoted path
+                if path.to_str().is_none_or(|path| path.contains(',')) {
+                    return Err(OnionError::InvalidTarget);
+                }
             }
         }
         for client_auth in &self.client_auths {
-            write!(f, " {}", client_auth)?;
+            let name = &client_auth.client_name;
+            if name.is_empty() || name.len() > 16 ||
+               !name.chars().all(|c| c.is_ascii_alphanumeric() || "+-_".contains(c)) {
+                return Err(OnionError::InvalidClientName(name.clone()));
+            }
         }
         Ok(())
     }
 }
 
-#[derive(Debug)]
+impl AddOnion {
+    fn command(&self) -> CommandBuilder {
+        let mut cmd = CommandBuilder::new("ADD_ONION");
+        cmd.arg(&self.key);
+        if !self.flags.is_empty() {
+            let flags = self.flags.iter().map(|flag| flag.to_string()).collect::<Vec<_>>();
+            cmd.key_value("Flags", flags.join(","));
+        }
+        if let Some(max_streams) = self.max_streams {
+            cmd.key_value("MaxStreams", max_streams);
+        }
+        if self.pow_defense.enabled {
+            cmd.key_value("PoWDefensesEnabled", 1);
+            if let Some(queue_rate) = self.pow_defense.queue_rate {
+                cmd.key_value("PoWQueueRate", queue_rate);
+            }
+            if let Some(queue_burst) = self.pow_defense.queue_burst {
+                cmd.key_value("PoWQueueBurst", queue_burst);
+            }
+        }
+        for &(virt_port, ref target) in &self.ports {
+            match *target {
+                None => cmd.key_value("Port", virt_port),
+                Some(ref target) => cmd.key_value("Port", format!("{},{}", virt_port, target)),
+            };
+        }
+        for client_auth in &self.client_auths {
+            cmd.key_value("ClientAuth", client_auth.value());
+        }
+        for client_auth in &self.client_auths_v3 {
+            cmd.key_value("ClientAuthV3", base32_encode(&client_auth.public_key));
+        }
+        cmd
+    }
+}
+
+impl fmt::Display for AddOnion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.command())
+    }
+}
+
+// Rate limiting of the introduction requests, done by the introduction points of the service
+// (HiddenServiceEnableIntroDoS* options).  Unset values are left to tor, which defaults to 25
+// introductions per second with bursts of 200.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IntroDosDefense {
+    pub enabled: bool,
+    pub rate_per_sec: Option<u32>,
+    pub burst_per_sec: Option<u32>,
+}
+
+impl IntroDosDefense {
+    pub fn validate(&self) -> Result<(), OnionError> {
+        validate_defense(self.enabled,
+                         ("HiddenServiceEnableIntroDoSRatePerSec", self.rate_per_sec, 25),
+                         ("HiddenServiceEnableIntroDoSBurstPerSec", self.burst_per_sec, 200))
+    }
+}
+
+// Proof-of-work asked to clients when the service is under load (HiddenServicePoW* options).
+// Unset values are left to tor, which defaults to 250 requests per second dequeued with bursts
+// of 2500.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PowDefense {
+    pub enabled: bool,
+    pub queue_rate: Option<u32>,
+    pub queue_burst: Option<u32>,
+}
+
+impl PowDefense {
+    pub fn validate(&self) -> Result<(), OnionError> {
+        validate_defense(self.enabled,
+                         ("HiddenServicePoWQueueRate", self.queue_rate, 250),
+                         ("HiddenServicePoWQueueBurst", self.queue_burst, 2500))
+    }
+}
+
+// Tor reads the rate and burst as positive ints, and refuses a burst below the rate once the
+// defense is enabled.  The defaults are tor's.
+fn validate_defense(enabled: bool,
+                    (rate_name, rate, default_rate): (&str, Option<u32>, u32),
+                    (burst_name, burst, default_burst): (&str, Option<u32>, u32))
+                    -> Result<(), OnionError> {
+    if rate.is_some_and(|rate| rate > i32::MAX as u32) {
+        return Err(OnionError::InvalidDefense(rate_name.to_string()));
+    }
+    if burst.is_some_and(|burst| burst > i32::MAX as u32) {
+        return Err(OnionError::InvalidDefense(burst_name.to_string()));
+    }
+    if enabled && burst.unwrap_or(default_burst) < rate.unwrap_or(default_rate) {
+        return Err(OnionError::InvalidDefense(burst_name.to_string()));
+    }
+    Ok(())
+}
+
+// Where the connections to a virtual port of an onion service are forwarded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OnionTarget {
+    // Port on localhost
+    Port(u16),
+    Addr(SocketAddr),
+    // Address or hostname, on the virtual port if no port is given, like "127.0.0.1" or
+    // "localhost:8080"
+    Host(String, Option<u16>),
+    Unix(PathBuf),
+    // Target that couldn't be parsed, written back as it was read
+    Other(String),
+}
+
+impl fmt::Display for OnionTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OnionTarget::Port(port) => write!(f, "{}", port),
+            OnionTarget::Addr(ref addr) => write!(f, "{}", addr),
+            OnionTarget::Host(ref host, Some(port)) => write!(f, "{}:{}", host, port),
+            OnionTarget::Host(ref host, None) => write!(f, "{}", host),
+            OnionTarget::Unix(ref path) => {
+                write!(f, "unix:{}", quote_if_needed(&path.to_string_lossy()))
+            }
+            OnionTarget::Other(ref target) => write!(f, "{}", target),
+        }
+    }
+}
+
+// Parses a target as written in HiddenServicePort options.
+impl FromStr for OnionTarget {
+    type Err = OnionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            let path = unquote(path).map_err(|_| OnionError::InvalidTarget)?;
+            return Ok(OnionTarget::Unix(PathBuf::from(path)));
+        }
+        if let Ok(port) = s.parse() {
+            return Ok(OnionTarget::Port(port));
+        }
+        if let Ok(addr) = s.parse() {
+            return Ok(OnionTarget::Addr(addr));
+        }
+        // The port of a bracketed IPv6 address follows the closing bracket
+        let (host, port) = match s.rfind(':') {
+            Some(colon) if !s.ends_with(']') => {
+                let port = s[colon + 1..].parse().map_err(|_| OnionError::InvalidTarget)?;
+                (&s[..colon], Some(port))
+            }
+            _ => (s, None),
+        };
+        let bracketed = host.starts_with('[') && host.ends_with(']');
+        if host.is_empty() || host.chars().all(|c| c.is_ascii_digit()) ||
+           host.chars().any(|c| c == ' ' || c.is_control()) ||
+           (host.contains(':') && !bracketed) {
+            return Err(OnionError::InvalidTarget);
+        }
+        Ok(OnionTarget::Host(host.to_string(), port))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum OnionKey {
     New(KeyType),
     Rsa1024(String),
+    // Base64 encoding of the 64 bytes expanded ed25519 secret key
+    Ed25519V3(String),
+}
+
+impl OnionKey {
+    // Builds the key from the "KeyType:KeyBlob" parts returned by tor.
+    fn from_blob(key_type: &str, blob: &str) -> Result<OnionKey, Error> {
+        match key_type {
+            "RSA1024" => Ok(OnionKey::Rsa1024(blob.to_string())),
+            "ED25519-V3" => Ok(OnionKey::Ed25519V3(blob.to_string())),
+            _ => Err(Error::ParseReply(ParseReplyError::UnknownKeyType(key_type.to_string()))),
+        }
+    }
 }
 
 impl fmt::Display for OnionKey {
@@ -144,13 +864,15 @@ impl fmt::Display for OnionKey {
         match self {
             &OnionKey::New(ref key_type) => write!(f, "NEW:{}", key_type),
             &OnionKey::Rsa1024(ref key) => write!(f, "RSA1024:{}", key),
+            &OnionKey::Ed25519V3(ref key) => write!(f, "ED25519-V3:{}", key),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum KeyType {
     Rsa1024,
+    Ed25519V3,
     Best,
 }
 
@@ -158,16 +880,23 @@ impl fmt::Display for KeyType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             &KeyType::Rsa1024 => write!(f, "RSA1024"),
+            &KeyType::Ed25519V3 => write!(f, "ED25519-V3"),
             &KeyType::Best => write!(f, "BEST"),
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum OnionFlags {
     DiscardPK,
     Detach,
     BasicAuth,
+    V3Auth,
+    // Single onion service, requires HiddenServiceSingleHopMode and
+    // HiddenServiceNonAnonymousMode to be set in tor
+    NonAnonymous,
+    // Close the circuit when MaxStreams is exceeded, instead of just rejecting the stream
+    MaxStreamsCloseCircuit,
 }
 
 impl fmt::Display for OnionFlags {
@@ -176,47 +905,146 @@ impl fmt::Display for OnionFlags {
             &OnionFlags::DiscardPK => write!(f, "DiscardPK"),
             &OnionFlags::Detach => write!(f, "Detach"),
             &OnionFlags::BasicAuth => write!(f, "BasicAuth"),
+            &OnionFlags::V3Auth => write!(f, "V3Auth"),
+            &OnionFlags::NonAnonymous => write!(f, "NonAnonymous"),
+            &OnionFlags::MaxStreamsCloseCircuit => write!(f, "MaxStreamsCloseCircuit"),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct OnionClientAuth {
     pub client_name: String,
     pub client_blob: Option<String>,
 }
 
+impl OnionClientAuth {
+    // "ClientName[:ClientBlob]", as given to ADD_ONION
+    fn value(&self) -> String {
+        match self.client_blob {
+            Some(ref client_blob) => format!("{}:{}", self.client_name, client_blob),
+            None => self.client_name.clone(),
+        }
+    }
+}
+
 impl fmt::Display for OnionClientAuth {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "ClientAuth={}", self.client_name)?;
-        if let &Some(ref client_blob) = &self.client_blob {
-            write!(f, ":{}", client_blob)?;
+        write!(f, "ClientAuth={}", self.value())
+    }
+}
+
+// Client allowed to access a v3 onion service, used with the V3Auth flag.  The key pair can be
+// generated with onion::ClientAuthKeypair.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OnionClientAuthV3 {
+    // x25519 public key of the client
+    pub public_key: [u8; 32],
+}
+
+// Client side credentials to access a v3 onion service with client authorization.
+#[derive(Clone, PartialEq)]
+pub struct OnionClientAuthCredential {
+    pub service_id: ServiceID,
+    // x25519 secret key of the client
+    pub secret_key: [u8; 32],
+    pub client_name: Option<String>,
+    // Store the credentials in ClientOnionAuthDir so that they survive restarts of tor
+    pub permanent: bool,
+}
+
+impl OnionClientAuthCredential {
+    // Parses the arguments of a CLIENT line in the ONION_CLIENT_AUTH_VIEW reply:
+    // "HSAddress KeyType:PrivateKeyBlob [ClientName=Nickname] [Flags=FLAGS]"
+    fn parse(s: &str) -> Result<OnionClientAuthCredential, Error> {
+        let args = ReplyArgs::parse(s)?;
+        let service_id = ServiceID::wrap(args.arg(0)
+            .ok_or(Error::ParseReply(ParseReplyError::MissingField))?
+            .to_string());
+        let key_value_parts: Vec<&str> = args.arg(1)
+            .ok_or(Error::ParseReply(ParseReplyError::MissingField))?
+            .splitn(2, ':')
+            .collect();
+        if key_value_parts.len() != 2 {
+            return Err(Error::ParseReply(ParseReplyError::MissingField));
         }
-        Ok(())
+        if key_value_parts[0] != "x25519" {
+            let key_type = key_value_parts[0].to_string();
+            return Err(Error::ParseReply(ParseReplyError::UnknownKeyType(key_type)));
+        }
+        let secret_key = match base64_decode(key_value_parts[1]) {
+            Some(ref key) if key.len() == 32 => {
+                let mut secret_key = [0u8; 32];
+                secret_key.copy_from_slice(key);
+                secret_key
+            }
+            _ => return Err(Error::Onion(OnionError::InvalidKeyBlob)),
+        };
+        let client_name = args.value("ClientName").map(|name| name.to_string());
+        let permanent = args.value("Flags")
+            .is_some_and(|flags| flags.split(',').any(|flag| flag == "Permanent"));
+        Ok(OnionClientAuthCredential { service_id, secret_key, client_name, permanent })
+    }
+
+    fn command(&self) -> CommandBuilder {
+        let mut cmd = CommandBuilder::new("ONION_CLIENT_AUTH_ADD");
+        cmd.arg(&self.service_id).arg(format!("x25519:{}", base64_encode(&self.secret_key)));
+        if let Some(ref client_name) = self.client_name {
+            cmd.key_value("ClientName", client_name);
+        }
+        if self.permanent {
+            cmd.key_value("Flags", "Permanent");
+        }
+        cmd
     }
 }
 
-#[derive(Debug)]
+impl fmt::Debug for OnionClientAuthCredential {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OnionClientAuthCredential")
+            .field("service_id", &self.service_id)
+            .field("client_name", &self.client_name)
+            .field("permanent", &self.permanent)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ServiceID(String);
 
-fn validate_sid(s: &str) {
-    let re: Regex = Regex::new("^[a-zA-Z2-7]{16}$").unwrap();
-    if !re.is_match(s) {
-        panic!("invalid ServiceID ".to_owned() + s)
+// Accepts both v2 (16 characters) and v3 (56 characters) onion addresses, without ".onion".
+// v3 addresses must have a valid checksum.
+fn validate_sid(s: &str) -> Result<(), OnionError> {
+    match s.len() {
+        16 => {
+            if !s.bytes().all(|c| c.is_ascii_alphabetic() || (b'2'..=b'7').contains(&c)) {
+                return Err(OnionError::InvalidBase32);
+            }
+            Ok(())
+        }
+        56 => s.parse::<OnionAddress>().map(|_| ()),
+        len => Err(OnionError::InvalidAddressLength(len)),
     }
 }
 
 impl ServiceID {
-    fn wrap(s: String) -> Self {
+    pub(crate) fn wrap(s: String) -> Self {
         ServiceID(s)
     }
 }
 
-impl<T: Into<String>> From<T> for ServiceID {
-    fn from(s: T) -> Self {
-        let st = s.into();
-        validate_sid(&st);
-        ServiceID(st)
+impl FromStr for ServiceID {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        validate_sid(s).map_err(Error::Onion)?;
+        Ok(ServiceID(s.to_string()))
+    }
+}
+
+impl fmt::Display for ServiceID {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
     }
 }
 
@@ -226,10 +1054,19 @@ impl<'a> AsRef<str> for ServiceID {
     }
 }
 
+// Results of a descriptor upload, by HSDir
+#[derive(Debug, Default)]
+pub struct HsPostResult {
+    pub uploaded: Vec<String>,
+    pub failed: Vec<(String, Option<HsDescReason>)>,
+    // HSDirs that didn't report a result before the timeout
+    pub pending: Vec<String>,
+}
+
 #[derive(Debug)]
 pub struct AddOnionReply {
     pub service_id: ServiceID,
-    pub sk: Option<String>,
+    pub sk: Option<OnionKey>,
     pub client_auths: Vec<(String, String)>,
 }
 
@@ -243,6 +1080,29 @@ pub struct Controller<T: Read + Write> {
     pub(crate) con: Connection<T>, /*    auth: Auth,
                                     *    connection: Connection,
                                     *    hash_pass: Option<&str>, */
+    // Events received while waiting for a command reply, or skipped while waiting for a
+    // specific event.  At most MAX_QUEUED_EVENTS are kept, and the ones no longer subscribed to
+    // are dropped.
+    pub(crate) events: VecDeque<Event>,
+    // Event names currently requested with SETEVENTS.  A name may appear more than once when
+    // several helpers are interested in it; it stays enabled until all of them remove it.
+    pub(crate) subscribed: Vec<String>,
+    // Services whose OnionService handle was dropped, deleted before the next command is sent.
+    pub(crate) dropped_onions: Arc<Mutex<Vec<ServiceID>>>,
+}
+
+// Events kept for later beyond this number are dropped, oldest first.
+pub const MAX_QUEUED_EVENTS: usize = 1000;
+
+// Streams that can wait for incoming data with a timeout, needed to wait for async events.
+pub trait ReadTimeout {
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()>;
+}
+
+impl ReadTimeout for TcpStream {
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, dur)
+    }
 }
 
 #[derive(Debug)]
@@ -254,6 +1114,11 @@ pub enum Error {
     ParseReply(ParseReplyError),
     Auth(AuthError),
     Reply(ReplyError),
+    Circuit(CircuitError),
+    Resolve(ResolveError),
+    HsDesc(HsDescError),
+    Onion(OnionError),
+    InvalidArgument(String),
 }
 
 #[derive(Debug)]
@@ -264,6 +1129,7 @@ pub enum RawReplyError {
     InvalidReplyLine,
     InvalidStatusCode,
     InvalidReply,
+    UnexpectedReply,
 }
 
 #[derive(Debug)]
@@ -273,26 +1139,270 @@ pub enum ParseReplyError {
     RegexCapture,
     FromHexError(hex::FromHexError),
     KeyNotFound,
+    UnexpectedEvent,
+    UnknownKeyType(String),
+    UnsupportedProtocolInfoVersion(u8),
+    InvalidQuotedString,
+    // The value of the key can't be parsed to the requested type
+    InvalidValue(String),
 }
 
+// Negative reply from tor, with the first line of the reply (after the code).
 #[derive(Debug)]
 pub struct ReplyError {
-    code: u16,
-    status: ReplyStatus,
-    line: String,
+    pub code: ReplyCode,
+    pub line: String,
+}
+
+impl ReplyError {
+    pub fn status(&self) -> ReplyStatus {
+        self.code.status()
+    }
 }
 
 #[derive(Debug)]
 pub enum AuthError {
     ServerNotVerified,
     AuthFailed(ReplyError),
+    // PROTOCOLINFO didn't give the path of the authentication cookie
+    NoCookieFile,
+}
+
+#[derive(Debug)]
+pub enum CircuitError {
+    // The circuit failed or was closed before being built, with the reason given by tor.
+    Failed(Option<CircuitReason>),
+    Timeout,
+}
+
+#[derive(Debug)]
+pub enum ResolveError {
+    // Tor could not resolve the address, with the error code given by tor.
+    Failed(Option<String>),
+    Timeout,
+}
+
+#[derive(Debug)]
+pub enum HsDescError {
+    // All the HSDirs failed, with the reason given by tor for the last failure.
+    Failed(Option<HsDescReason>),
+    Timeout,
 }
 
 #[derive(Debug)]
 pub enum OnionError {
     NoPortGiven,
+    // Client authorizations are given without the flag, or the flag without clients
+    ClientAuthWithoutFlag(OnionFlags),
+    // BasicAuth needs a RSA1024 key, V3Auth an ED25519-V3 key
+    KeyTypeMismatch,
+    InvalidClientName(String),
+    // The option of a DoS defense is out of range, or a burst is below its rate
+    InvalidDefense(String),
+    // NonAnonymous is given but tor is not configured for single onion services
+    NonAnonymousNotConfigured,
+    // Unix socket paths must be valid UTF-8 without commas
+    InvalidTarget,
+    InvalidAddressLength(usize),
+    InvalidBase32,
+    InvalidVersion(u8),
+    InvalidChecksum,
+    InvalidKeyBlob,
+    InvalidKeyFile,
+    // The key files of a HiddenServiceDir don't belong to the same service
+    KeyMismatch,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Stream(ref err) => write!(f, "control connection error: {}", err),
+            Error::StringParse(ref err) => write!(f, "invalid integer: {}", err),
+            Error::Regex(ref err) => write!(f, "invalid regex: {}", err),
+            Error::RawReply(ref err) => write!(f, "invalid reply from tor: {}", err),
+            Error::ParseReply(ref err) => write!(f, "unexpected reply from tor: {}", err),
+            Error::Auth(ref err) => write!(f, "authentication error: {}", err),
+            Error::Reply(ref err) => write!(f, "{}", err),
+            Error::Circuit(ref err) => write!(f, "{}", err),
+            Error::Resolve(ref err) => write!(f, "{}", err),
+            Error::HsDesc(ref err) => write!(f, "{}", err),
+            Error::Onion(ref err) => write!(f, "{}", err),
+            Error::InvalidArgument(ref arg) => write!(f, "invalid argument: {}", arg),
+        }
+    }
 }
 
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Stream(ref err) => Some(err),
+            Error::StringParse(ref err) => Some(err),
+            Error::Regex(ref err) => Some(err),
+            Error::RawReply(ref err) => Some(err),
+            Error::ParseReply(ref err) => Some(err),
+            Error::Auth(ref err) => Some(err),
+            Error::Reply(ref err) => Some(err),
+            Error::Circuit(ref err) => Some(err),
+            Error::Resolve(ref err) => Some(err),
+            Error::HsDesc(ref err) => Some(err),
+            Error::Onion(ref err) => Some(err),
+            Error::InvalidArgument(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for RawReplyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RawReplyError::NonNumericStatusCode(ref err) => {
+                write!(f, "non numeric status code: {}", err)
+            }
+            RawReplyError::VaryingStatusCode => write!(f, "status code changed within the reply"),
+            RawReplyError::InvalidReplyMode => write!(f, "invalid reply mode"),
+            RawReplyError::InvalidReplyLine => write!(f, "invalid reply line"),
+            RawReplyError::InvalidStatusCode => write!(f, "invalid status code"),
+            RawReplyError::InvalidReply => write!(f, "invalid reply"),
+            RawReplyError::UnexpectedReply => write!(f, "unexpected reply"),
+        }
+    }
+}
+
+impl error::Error for RawReplyError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            RawReplyError::NonNumericStatusCode(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ParseReplyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseReplyError::MissingField => write!(f, "missing field"),
+            ParseReplyError::ParseIntError(ref err) => write!(f, "invalid integer: {}", err),
+            ParseReplyError::RegexCapture => write!(f, "unexpected format"),
+            ParseReplyError::FromHexError(ref err) => write!(f, "invalid hex: {}", err),
+            ParseReplyError::KeyNotFound => write!(f, "key not found"),
+            ParseReplyError::UnexpectedEvent => write!(f, "unexpected event"),
+            ParseReplyError::UnknownKeyType(ref key_type) => {
+                write!(f, "unknown key type {}", key_type)
+            }
+            ParseReplyError::UnsupportedProtocolInfoVersion(version) => {
+                write!(f, "unsupported PROTOCOLINFO version {}", version)
+            }
+            ParseReplyError::InvalidQuotedString => write!(f, "invalid quoted string"),
+            ParseReplyError::InvalidValue(ref key) => write!(f, "invalid value for {}", key),
+        }
+    }
+}
+
+impl error::Error for ParseReplyError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            ParseReplyError::ParseIntError(ref err) => Some(err),
+            ParseReplyError::FromHexError(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ReplyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "tor replied {} {}", self.code, self.line)
+    }
+}
+
+impl error::Error for ReplyError {}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AuthError::ServerNotVerified => write!(f, "the server hash doesn't match"),
+            AuthError::AuthFailed(ref err) => write!(f, "{}", err),
+            AuthError::NoCookieFile => write!(f, "tor gave no cookie file"),
+        }
+    }
+}
+
+impl error::Error for AuthError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            AuthError::AuthFailed(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for CircuitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CircuitError::Failed(Some(ref reason)) => write!(f, "circuit failed: {}", reason),
+            CircuitError::Failed(None) => write!(f, "circuit failed"),
+            CircuitError::Timeout => write!(f, "timed out building the circuit"),
+        }
+    }
+}
+
+impl error::Error for CircuitError {}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ResolveError::Failed(Some(ref error)) => write!(f, "resolve failed: {}", error),
+            ResolveError::Failed(None) => write!(f, "resolve failed"),
+            ResolveError::Timeout => write!(f, "timed out resolving the address"),
+        }
+    }
+}
+
+impl error::Error for ResolveError {}
+
+impl fmt::Display for HsDescError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HsDescError::Failed(Some(ref reason)) => {
+                write!(f, "descriptor request failed: {}", reason)
+            }
+            HsDescError::Failed(None) => write!(f, "descriptor request failed"),
+            HsDescError::Timeout => write!(f, "timed out waiting for the descriptor"),
+        }
+    }
+}
+
+impl error::Error for HsDescError {}
+
+impl fmt::Display for OnionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OnionError::NoPortGiven => write!(f, "no port given"),
+            OnionError::ClientAuthWithoutFlag(flag) => {
+                write!(f, "client authorizations and the {} flag must be given together", flag)
+            }
+            OnionError::KeyTypeMismatch => write!(f, "options not supported by the key type"),
+            OnionError::InvalidClientName(ref name) => write!(f, "invalid client name {}", name),
+            OnionError::NonAnonymousNotConfigured => {
+                write!(f, "NonAnonymous needs tor to be configured for single onion services")
+            }
+            OnionError::InvalidDefense(ref option) => write!(f, "invalid value for {}", option),
+            OnionError::InvalidTarget => write!(f, "invalid port target"),
+            OnionError::InvalidAddressLength(len) => {
+                write!(f, "invalid onion address length {}", len)
+            }
+            OnionError::InvalidBase32 => write!(f, "invalid base32 in onion address"),
+            OnionError::InvalidVersion(version) => {
+                write!(f, "unsupported onion address version {}", version)
+            }
+            OnionError::InvalidChecksum => write!(f, "invalid onion address checksum"),
+            OnionError::InvalidKeyBlob => write!(f, "invalid key blob"),
+            OnionError::InvalidKeyFile => write!(f, "invalid key file"),
+            OnionError::KeyMismatch => write!(f, "the keys don't belong to the same service"),
+        }
+    }
+}
+
+impl error::Error for OnionError {}
+
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Self {
         Error::Stream(err)
@@ -317,17 +1427,259 @@ impl From<hex::FromHexError> for Error {
     }
 }
 
-fn write_join<T: fmt::Display>(f: &mut fmt::Formatter, elems: &Vec<T>, sep: &str) -> fmt::Result {
-    let mut first = true;
-    for e in elems {
-        if first {
-            first = false;
-        } else {
-            write!(f, "{}", sep)?;
+// Strings containing spaces or special characters are sent and received as QuotedStrings
+// (section 2.1.1 of the control spec), which tor escapes like C strings: \n, \t, \r, \\, \",
+// \', \xHH and octal \OOO.  Non-ASCII bytes are usually escaped in octal.
+
+// Quotes s as a QuotedString, escaping quotes, backslashes and control characters.
+pub fn quote(s: &str) -> String {
+    let mut res = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if c.is_control() => {
+                let mut buf = [0u8; 4];
+                for byte in c.encode_utf8(&mut buf).bytes() {
+                    res.push_str(&format!("\\{:03o}", byte));
+                }
+            }
+            c => res.push(c),
         }
-        write!(f, "{}", e)?;
     }
-    Ok(())
+    res.push('"');
+    res
+}
+
+// Quotes s only if it wouldn't be read back as a single argument otherwise.
+fn quote_if_needed(s: &str) -> String {
+    if s.is_empty() || s.chars().any(|c| c == ' ' || c == '"' || c == '\\' || c.is_control()) {
+        quote(s)
+    } else {
+        s.to_string()
+    }
+}
+
+// Command sent to tor.  Arguments containing control characters are rejected, as a "\r\n" would
+// end the command and let the rest of the argument be read as another command.  Values are
+// quoted when needed.
+#[derive(Debug, Clone)]
+pub struct CommandBuilder {
+    line: String,
+    data: Option<String>,
+    // First rejected argument
+    invalid: Option<String>,
+}
+
+impl CommandBuilder {
+    pub fn new(keyword: &str) -> Self {
+        let mut cmd = CommandBuilder {
+            line: String::new(),
+            data: None,
+            invalid: None,
+        };
+        cmd.check(keyword, is_word(keyword));
+        cmd.line.push_str(keyword);
+        cmd
+    }
+
+    // Adds an argument sent as it is, like an id or a flag, which can't contain spaces.
+    pub fn arg<A: fmt::Display>(&mut self, arg: A) -> &mut Self {
+        let arg = arg.to_string();
+        self.check(&arg, is_word(&arg));
+        self.line.push(' ');
+        self.line.push_str(&arg);
+        self
+    }
+
+    // Adds an argument quoted if needed, like a path.
+    pub fn quoted_arg<A: fmt::Display>(&mut self, arg: A) -> &mut Self {
+        let arg = arg.to_string();
+        self.check(&arg, !arg.chars().any(|c| c.is_control()));
+        self.line.push(' ');
+        self.line.push_str(&quote_if_needed(&arg));
+        self
+    }
+
+    // Adds a "Key=Value" argument, with the value quoted if needed.
+    pub fn key_value<V: fmt::Display>(&mut self, key: &str, value: V) -> &mut Self {
+        let value = value.to_string();
+        self.check(key, is_word(key) && !key.contains('='));
+        self.check(&value, !value.chars().any(|c| c.is_control()));
+        self.line.push_str(&format!(" {}={}", key, quote_if_needed(&value)));
+        self
+    }
+
+    // Makes it a multi-line command, sending the data after the arguments.
+    pub fn data(&mut self, data: &str) -> &mut Self {
+        self.data = Some(data.to_string());
+        self
+    }
+
+    // The command to give to raw_cmd, or an error if an argument was rejected.
+    pub fn build(&self) -> Result<String, Error> {
+        if let Some(ref invalid) = self.invalid {
+            return Err(Error::InvalidArgument(format!("{:?}", invalid)));
+        }
+        match self.data {
+            Some(ref data) => Ok(format!("+{}\r\n{}.", self.line, dot_encode(data))),
+            None => Ok(self.line.clone()),
+        }
+    }
+
+    fn check(&mut self, arg: &str, valid: bool) {
+        if !valid && self.invalid.is_none() {
+            self.invalid = Some(arg.to_string());
+        }
+    }
+}
+
+// The first line of the command, without checking the arguments.
+impl fmt::Display for CommandBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.data.is_some() {
+            write!(f, "+")?;
+        }
+        write!(f, "{}", self.line)
+    }
+}
+
+fn is_word(s: &str) -> bool {
+    !s.is_empty() && !s.chars().any(|c| c == ' ' || c.is_control())
+}
+
+// Reads the QuotedString at the start of s, returning it unescaped along with the rest of s.
+pub fn read_quoted(s: &str) -> Result<(String, &str), Error> {
+    let invalid = || Error::ParseReply(ParseReplyError::InvalidQuotedString);
+    let bytes = s.as_bytes();
+    if bytes.first() != Some(&b'"') {
+        return Err(invalid());
+    }
+    let mut res = Vec::new();
+    let mut i = 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'"' => {
+                let unquoted = String::from_utf8(res).map_err(|_| invalid())?;
+                return Ok((unquoted, &s[i + 1..]));
+            }
+            b'\\' => {
+                i += 1;
+                match bytes.get(i) {
+                    Some(&b'n') => res.push(b'\n'),
+                    Some(&b't') => res.push(b'\t'),
+                    Some(&b'r') => res.push(b'\r'),
+                    Some(&c @ b'\\') | Some(&c @ b'"') | Some(&c @ b'\'') => res.push(c),
+                    Some(&b'x') => {
+                        let hex = s.get(i + 1..i + 3).ok_or_else(invalid)?;
+                        res.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+                        i += 2;
+                    }
+                    Some(&(b'0'..=b'7')) => {
+                        let len = bytes[i..]
+                            .iter()
+                            .take(3)
+                            .take_while(|&&c| (b'0'..=b'7').contains(&c))
+                            .count();
+                        let value = u16::from_str_radix(&s[i..i + len], 8).map_err(|_| invalid())?;
+                        if value > 0xff {
+                            return Err(invalid());
+                        }
+                        res.push(value as u8);
+                        i += len - 1;
+                    }
+                    _ => return Err(invalid()),
+                }
+            }
+            c => res.push(c),
+        }
+        i += 1;
+    }
+    // Missing closing quote
+    Err(invalid())
+}
+
+// Unescapes s if it is a QuotedString, other strings are returned as they are.
+pub fn unquote(s: &str) -> Result<String, Error> {
+    if !s.starts_with('"') {
+        return Ok(s.to_string());
+    }
+    match read_quoted(s)? {
+        (unquoted, "") => Ok(unquoted),
+        _ => Err(Error::ParseReply(ParseReplyError::InvalidQuotedString)),
+    }
+}
+
+// Whether the ADDRMAP event gives the result of resolving address.  Tor reports the result of a
+// reverse lookup for the IP itself, and again for "REVERSE[<ip>]" when caching it.
+fn is_addrmap_for(event: &Event, address: &str, reverse: bool) -> bool {
+    if event.keyword != "ADDRMAP" {
+        return false;
+    }
+    let mut mapped = event.args().split(' ').next().unwrap_or("");
+    if reverse {
+        mapped = mapped.strip_prefix("REVERSE[")
+            .and_then(|mapped| mapped.strip_suffix(']'))
+            .unwrap_or(mapped);
+    }
+    mapped.eq_ignore_ascii_case(address)
+}
+
+// Whether the event is about the descriptor of the onion service at address (without ".onion")
+fn is_hs_desc_for(event: &Event, address: &str) -> bool {
+    match event.keyword.as_str() {
+        "HS_DESC" => event.args().split(' ').nth(1) == Some(address),
+        "HS_DESC_CONTENT" => event.args().split(' ').next() == Some(address),
+        _ => false,
+    }
+}
+
+// Parses the service ids listed one per line by GETINFO onions/*.
+fn parse_service_ids(data: &str) -> Result<Vec<ServiceID>, Error> {
+    data.lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| line.parse::<ServiceID>())
+        .collect()
+}
+
+// Splits "First:Second" values
+fn split_pair(value: &str) -> Result<(&str, &str), Error> {
+    value.split_once(':').ok_or(Error::ParseReply(ParseReplyError::MissingField))
+}
+
+// Whether cmd is a single command: one line, or for multi-line commands ("+KEYWORD ...") one line
+// followed by dot-encoded data and the terminating "." line.  Line breaks anywhere else would
+// let the rest be read as another command.
+fn is_single_cmd(cmd: &str) -> bool {
+    let is_line = |line: &&str| !line.contains(&['\r', '\n'][..]);
+    if !cmd.starts_with('+') {
+        return is_line(&cmd);
+    }
+    let lines = cmd.split("\r\n").collect::<Vec<_>>();
+    match lines.split_last() {
+        Some((&".", lines)) => {
+            lines.iter().all(is_line) &&
+            lines[1..].iter().all(|line| !line.starts_with('.') || line.starts_with(".."))
+        }
+        _ => false,
+    }
+}
+
+// Encodes data for a multi-line command: lines end in CRLF and lines beginning with a period get
+// an additional one.  The terminating "." line is not included.
+fn dot_encode(data: &str) -> String {
+    let mut res = String::new();
+    for line in data.lines() {
+        if line.starts_with('.') {
+            res.push('.');
+        }
+        res.push_str(line);
+        res.push_str("\r\n");
+    }
+    res
 }
 
 impl Connection<TcpStream> {
@@ -345,7 +1697,7 @@ impl Connection<TcpStream> {
 
 impl Controller<TcpStream> {
     pub fn from_addr<A: ToSocketAddrs>(addr: A) -> Result<Controller<TcpStream>, io::Error> {
-        Ok(Controller { con: Connection::<TcpStream>::connect(addr)? })
+        Ok(Controller::new(Connection::<TcpStream>::connect(addr)?))
     }
 
     pub fn from_port(port: u16) -> Result<Controller<TcpStream>, io::Error> {
@@ -353,11 +1705,21 @@ impl Controller<TcpStream> {
     }
 
     pub fn close(&mut self) -> Result<(), io::Error> {
+        self.del_dropped_onions().unwrap_or(());
         self.con.close()
     }
 }
 
 impl<T: Read + Write> Controller<T> {
+    pub(crate) fn new(con: Connection<T>) -> Self {
+        Controller {
+            con,
+            events: VecDeque::new(),
+            subscribed: Vec::new(),
+            dropped_onions: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
     pub fn authenticate(&mut self) -> Result<(), Error> {
         let protocolinfo = self.cmd_protocolinfo()?;
 
@@ -367,7 +1729,10 @@ impl<T: Read + Write> Controller<T> {
         let mut rng = rand::thread_rng();
         let client_nonce = rng.gen::<[u8; 32]>();
         let authchallenge = self.cmd_authchallenge(&client_nonce)?;
-        let mut cookie_file = File::open(protocolinfo.cookie_files[0].clone())?;
+        let cookie_file = protocolinfo.cookie_files
+            .first()
+            .ok_or(Error::Auth(AuthError::NoCookieFile))?;
+        let mut cookie_file = File::open(cookie_file)?;
         let mut cookie = Vec::new();
         cookie_file.read_to_end(&mut cookie)?;
         let sha256 = Sha256::new();
@@ -405,12 +1770,43 @@ impl<T: Read + Write> Controller<T> {
         self.cmd_getinfo("version")
     }
 
+    // Sends a command, built with CommandBuilder unless it has no arguments.  Commands that
+    // could be read by tor as several ones are refused, see is_single_cmd.
     pub fn raw_cmd(&mut self, cmd: &str) -> Result<Reply, Error> {
+        if !is_single_cmd(cmd) {
+            return Err(Error::InvalidArgument(format!("{:?}", cmd)));
+        }
+        self.del_dropped_onions()?;
         debug!("{}", cmd);
         self.con.buf_writer.write_all(cmd.as_bytes())?;
         self.con.buf_writer.write_all(b"\r\n")?;
         self.con.buf_writer.flush()?;
 
+        loop {
+            let (status_code, reply_lines) = self.read_reply()?;
+            // Async events can arrive before the reply to our command, keep them for later.
+            if status_code == 650 {
+                let event = Event::from_lines(reply_lines)?;
+                self.queue_event(event);
+                continue;
+            }
+            let code = ReplyCode::from(status_code);
+            match code.status() {
+                ReplyStatus::Positive => return Ok(Reply { lines: reply_lines }),
+                ReplyStatus::Unknown => {
+                    return Err(Error::RawReply(RawReplyError::InvalidStatusCode))
+                }
+                _ => (),
+            }
+            return Err(Error::Reply(ReplyError {
+                code,
+                line: reply_lines.first().map_or(String::new(), |line| line.reply.clone()),
+            }));
+        }
+    }
+
+    // Reads a complete reply (all the lines sharing the same status code) from the stream.
+    fn read_reply(&mut self) -> Result<(u16, Vec<ReplyLine>), Error> {
         let mut raw_line = String::new();
         let mut reply_lines = Vec::new();
         let mut multi_line = false;
@@ -455,7 +1851,6 @@ impl<T: Read + Write> Controller<T> {
                     status_code = status_code_str.parse::<u16>()
                         .map_err(|err| Error::RawReply(RawReplyError::NonNumericStatusCode(err)))?;
                 } else {
-                    // TODO Parse Async replies here
                     if code != status_code_str {
                         return Err(Error::RawReply(RawReplyError::VaryingStatusCode));
                     }
@@ -474,92 +1869,33 @@ impl<T: Read + Write> Controller<T> {
             raw_line.clear();
         }
 
-        let status = match status_code_str.chars().nth(0) {
-            Some('2') => return Ok(Reply { lines: reply_lines }),
-            Some('4') => ReplyStatus::TempNegative,
-            Some('5') => ReplyStatus::PermNegative,
-            Some('6') => ReplyStatus::Async,
-            _ => return Err(Error::RawReply(RawReplyError::InvalidStatusCode)),
-        };
-        Err(Error::Reply(ReplyError {
-            code: status_code,
-            status: status,
-            line: reply_lines[0].reply.clone(),
-        }))
+        if status_code_str.is_empty() {
+            return Err(Error::RawReply(RawReplyError::InvalidStatusCode));
+        }
+        Ok((status_code, reply_lines))
     }
 
     // PROTOCOLINFO
     pub fn cmd_protocolinfo(&mut self) -> Result<ProtocolInfo, Error> {
         let reply = self.raw_cmd("PROTOCOLINFO")?;
-        // regex for QuotedString = (\\.|[^\"])*
-        let re_protocolinfo = Regex::new(r"^PROTOCOLINFO (?P<version>[0-9]+)$")?;
-        let re_tor_version = Regex::new("^VERSION Tor=\"(?P<tor_version>(\\.|[^\"])*)\"[ ]*\
-                                        (?P<opt_arguments>.*)$")?;
-        let re_auth = Regex::new("^AUTH METHODS=(?P<auth_methods>[A-Z,]+)[ ]*\
-                                 (?P<maybe_cookie_files>.*)$")?;
-        let re_cookie_file = Regex::new("COOKIEFILE=\"(?P<cookie_file>(\\.|[^\"])*)\"")?;
-
-        let prot_inf = re_cap_or_err!(re_protocolinfo, reply.lines[0].reply.as_str());
-        let version_str = cap_name_or_err!(prot_inf, "version");
-        let version = version_str.parse::<u8>()
-            .map_err(|err| Error::ParseReply(ParseReplyError::ParseIntError(err)))?;
-        match version {
-            1 => (),
-            _ => panic!("Version {} not supported", version),
-        }
-
-        let mut tor_version = String::new();
-        let mut cookie_files = Vec::new();
-        let mut auth_methods = Vec::new();
-
-        for line in reply.lines.iter().skip(1) {
-            match line.reply.split(' ').nth(0) {
-                Some("AUTH") => {
-                    let auth = re_cap_or_err!(re_auth, &line.reply);
-                    auth_methods = cap_name_or_err!(auth, "auth_methods")
-                        .split(',')
-                        .map(|x| match x {
-                            "NULL" => AuthMethod::Null,
-                            "HASHEDPASSWORD" => AuthMethod::HashedPassword,
-                            "COOKIE" => AuthMethod::Cookie,
-                            "SAFECOOKIE" => AuthMethod::SafeCookie,
-                            _ => panic!("Auth method {} not supported", x),
-                        })
-                        .collect::<Vec<_>>();
-                    let maybe_cookie_files = cap_name_or_err!(auth, "maybe_cookie_files");
-                    for caps in re_cookie_file.captures_iter(maybe_cookie_files) {
-                        cookie_files.push(cap_name_or_err!(caps, "cookie_file").to_string());
-                    }
-                }
-                Some("VERSION") => {
-                    let ver = re_cap_or_err!(re_tor_version, &line.reply);
-                    tor_version = cap_name_or_err!(ver, "tor_version").to_string();
-                    // Not used so far
-                    // let opt_arguments = cap_name_or_err!(ver, "opt_arguments");
-                }
-                Some("OK") => (), // End of PROTOCOLINFO reply
-                _ => (), // Unrecognized InfoLine
-            }
-        }
-        Ok(ProtocolInfo {
-            protocol_info_ver: version,
-            tor_ver: tor_version,
-            auth_methods: auth_methods,
-            cookie_files: cookie_files,
-        })
+        ProtocolInfo::parse(&reply)
     }
 
     // AUTHCHALLENGE
     pub fn cmd_authchallenge(&mut self, client_nonce: &[u8; 32]) -> Result<AuthChallenge, Error> {
-        let reply = self.raw_cmd(format!("AUTHCHALLENGE SAFECOOKIE {}",
-                                         hex::encode(client_nonce))
-            .as_str())?;
-        let re_authchallenge = Regex::new("^AUTHCHALLENGE \
-                                           SERVERHASH=(?P<server_hash>[0-9A-F]{64}) \
-                                           SERVERNONCE=(?P<server_nonce>[0-9A-F]{64})$")?;
-        let server_challenge = re_cap_or_err!(re_authchallenge, reply.lines[0].reply.as_str());
-        let server_hash = cap_name_or_err!(server_challenge, "server_hash");
-        let server_nonce = cap_name_or_err!(server_challenge, "server_nonce");
+        let cmd = CommandBuilder::new("AUTHCHALLENGE")
+            .arg("SAFECOOKIE")
+            .arg(hex::encode(client_nonce))
+            .build()?;
+        let reply = self.raw_cmd(&cmd)?;
+        let server_challenge = ReplyArgs::parse(&reply.line(0)?.reply)?;
+        if server_challenge.arg(0) != Some("AUTHCHALLENGE") {
+            return Err(Error::RawReply(RawReplyError::UnexpectedReply));
+        }
+        let server_hash = server_challenge.value("SERVERHASH")
+            .ok_or(Error::ParseReply(ParseReplyError::MissingField))?;
+        let server_nonce = server_challenge.value("SERVERNONCE")
+            .ok_or(Error::ParseReply(ParseReplyError::MissingField))?;
 
         let res = AuthChallenge {
             server_hash: FromHex::from_hex(server_hash)?,
@@ -571,10 +1907,11 @@ impl<T: Read + Write> Controller<T> {
 
     // GETINFO
     // The output is not parsed (you are on your own), it's just a string containing the return
-    // value (the 'keyword=' part is stripped).
+    // value (the 'keyword=' part is stripped).  Values made of arguments, like the one of
+    // status/bootstrap-phase, can be parsed with ReplyArgs.
     pub fn cmd_getinfo(&mut self, info_key: &str) -> Result<String, Error> {
-        let reply = self.raw_cmd(format!("GETINFO {}", info_key).as_str())?;
-        let reply_line = &reply.lines[0];
+        let reply = self.raw_cmd(&CommandBuilder::new("GETINFO").arg(info_key).build()?)?;
+        let reply_line = reply.line(0)?;
         if !(reply_line.reply.starts_with(info_key) &&
              reply_line.reply.chars().nth(info_key.len()) == Some('=')) {
             return Err(Error::ParseReply(ParseReplyError::KeyNotFound));
@@ -586,31 +1923,19 @@ impl<T: Read + Write> Controller<T> {
     }
 
     pub fn cmd_getinfos(&mut self, args: &[&str]) -> Result<HashMap<String,Vec<String>>, Error> {
-        let mut req = String::from("GETINFO");
+        let mut req = CommandBuilder::new("GETINFO");
         let mut res: HashMap<String, Vec<String>> = HashMap::new();
 
         for arg in args.iter() {
-            req.push_str(&format!(" {}", arg));
+            req.arg(arg);
         }
 
-        for line in self.raw_cmd(&req)?.lines.iter() {
+        for line in self.raw_cmd(&req.build()?)?.lines.iter() {
             if line.reply == "OK" {
                 return Ok(res);
             }
-
-            let key_value_parts: Vec<&str> = line.reply.splitn(2, '=').collect();
-            let key = key_value_parts[0].to_string();
-
-            let mut value: Vec<String> = match res.get(&key) {
-                Some(val) => val.to_owned(),
-                None => Vec::new()
-            };
-
-            if key_value_parts.len() > 1 {
-                value.push(key_value_parts[1].to_string());
-            }
-
-            res.insert(key, value);
+            let (key, value) = ReplyArgs::key_value(&line.reply)?;
+            res.entry(key).or_default().extend(value);
         }
 
         // OK not returned
@@ -619,7 +1944,11 @@ impl<T: Read + Write> Controller<T> {
 
     // AUTHENTICATE
     pub fn cmd_authenticate(&mut self, pwd: &[u8]) -> Result<Reply, Error> {
-        self.raw_cmd(format!("AUTHENTICATE {}", hex::encode(pwd)).as_str())
+        let mut req = CommandBuilder::new("AUTHENTICATE");
+        if !pwd.is_empty() {
+            req.arg(hex::encode(pwd));
+        }
+        self.raw_cmd(&req.build()?)
     }
 
     // QUIT
@@ -629,82 +1958,179 @@ impl<T: Read + Write> Controller<T> {
 
     // ADD_ONION
     pub fn cmd_add_onion(&mut self, add_onion: AddOnion) -> Result<AddOnionReply, Error> {
-        // if add_onion.ports.is_empty() {
-        //    return Err(OnionError::NoPortGiven);
-        // }
-        // TODO: check client_auths.ClientName(s) is from 1 to 16 chars from A-Za-z0-9+-_
-        let re_service = Regex::new(r"^ServiceID=(?P<service_id>[^ ]+)$")?;
-        let re_sk = Regex::new(r"^PrivateKey=(?P<key_type>[^ ]+):(?P<sk>[^ ]+)$")?;
-        let re_client_auth = Regex::new("^ClientAuth=(?P<client_name>[^ ]+):\
-                                             (?P<client_blob>[^ ]+)$")?;
-        let cmd = format!("{}", add_onion);
-        let reply = self.raw_cmd(cmd.as_str())?;
-        let cap_service = re_cap_or_err!(re_service, reply.lines[0].reply.as_str());
-        let service_id = ServiceID(cap_name_or_err!(cap_service, "service_id").to_string());
-
-        let mut skip = 1;
-        let sk = match add_onion.key {
-            OnionKey::New(_) => {
-                if !add_onion.flags.contains(&OnionFlags::DiscardPK) {
-                    let cap_sk = re_cap_or_err!(re_sk, reply.lines[1].reply.as_str());
-                    skip = 2;
-                    Some(cap_name_or_err!(cap_sk, "sk").to_string())
-                } else {
-                    None
-                }
-            }
-            _ => None,
-        };
-
+        add_onion.validate().map_err(Error::Onion)?;
+        if add_onion.flags.contains(&OnionFlags::NonAnonymous) {
+            self.check_single_onion()?;
+        }
+        let reply = self.raw_cmd(&add_onion.command().build()?)?;
+        let mut service_id = None;
+        let mut sk = None;
         let mut client_auths = Vec::new();
-        for line in reply.lines.iter().skip(skip).take(add_onion.client_auths.len()) {
-            let reply = &line.reply;
-            let cap_client_auth = re_cap_or_err!(re_client_auth, reply.as_str());
-            let client_name = cap_name_or_err!(cap_client_auth, "client_name").to_string();
-            let client_blob = cap_name_or_err!(cap_client_auth, "client_blob").to_string();
-            client_auths.push((client_name, client_blob));
+        for line in reply.lines.iter() {
+            let args = ReplyArgs::parse(&line.reply)?;
+            if let Some(id) = args.value("ServiceID") {
+                service_id = Some(ServiceID::wrap(id.to_string()));
+            }
+            // "PrivateKey=KeyType:KeyBlob", "ClientAuth=ClientName:ClientBlob"
+            if let Some(key) = args.value("PrivateKey") {
+                let (key_type, blob) = split_pair(key)?;
+                sk = Some(OnionKey::from_blob(key_type, blob)?);
+            }
+            if let Some(client_auth) = args.value("ClientAuth") {
+                let (client_name, client_blob) = split_pair(client_auth)?;
+                client_auths.push((client_name.to_string(), client_blob.to_string()));
+            }
         }
+        let service_id = service_id.ok_or(Error::ParseReply(ParseReplyError::KeyNotFound))?;
         Ok(AddOnionReply { service_id, sk, client_auths })
     }
 
+    // ONION_CLIENT_AUTH_ADD
+    pub fn cmd_onion_client_auth_add(&mut self,
+                                     credential: &OnionClientAuthCredential)
+                                     -> Result<(), Error> {
+        self.raw_cmd(&credential.command().build()?).map(|_|())
+    }
+
+    // ONION_CLIENT_AUTH_REMOVE
+    pub fn cmd_onion_client_auth_remove(&mut self, service_id: &ServiceID) -> Result<(), Error> {
+        let req = CommandBuilder::new("ONION_CLIENT_AUTH_REMOVE").arg(service_id).build()?;
+        self.raw_cmd(&req).map(|_|())
+    }
+
+    // ONION_CLIENT_AUTH_VIEW
+    // Lists the credentials known by tor, only the ones for service_id if given.
+    pub fn cmd_onion_client_auth_view(&mut self,
+                                      service_id: Option<&ServiceID>)
+                                      -> Result<Vec<OnionClientAuthCredential>, Error> {
+        let mut req = CommandBuilder::new("ONION_CLIENT_AUTH_VIEW");
+        if let Some(service_id) = service_id {
+            req.arg(service_id);
+        }
+        let mut credentials = Vec::new();
+        for line in self.raw_cmd(&req.build()?)?.lines.iter() {
+            if let Some(client) = line.reply.strip_prefix("CLIENT ") {
+                credentials.push(OnionClientAuthCredential::parse(client)?);
+            }
+        }
+        Ok(credentials)
+    }
+
+    // Tor only accepts NonAnonymous services when it is configured as a single onion service.
+    fn check_single_onion(&mut self) -> Result<(), Error> {
+        let conf = self.cmd_getconf(&["HiddenServiceSingleHopMode",
+                                      "HiddenServiceNonAnonymousMode"])?;
+        let enabled = |key: &str| {
+            conf.get(key).and_then(|values| values.first()).is_some_and(|value| value == "1")
+        };
+        if !enabled("HiddenServiceSingleHopMode") || !enabled("HiddenServiceNonAnonymousMode") {
+            return Err(Error::Onion(OnionError::NonAnonymousNotConfigured));
+        }
+        Ok(())
+    }
+
     // DEL_ONION
     pub fn cmd_del_onion(&mut self, service_id: ServiceID) -> Result<(), Error> {
-        self.raw_cmd(&format!("DEL_ONION {}", service_id.as_ref())).map(|_|())
+        self.raw_cmd(&CommandBuilder::new("DEL_ONION").arg(service_id).build()?).map(|_|())
+    }
+
+    // Deletes the service, unless tor doesn't know it (anymore).
+    pub(crate) fn del_onion_if_exists(&mut self, service_id: ServiceID) -> Result<(), Error> {
+        match self.cmd_del_onion(service_id) {
+            Err(Error::Reply(ReplyError { code: ReplyCode::UnrecognizedEntity, .. })) => Ok(()),
+            res => res,
+        }
+    }
+
+    fn del_dropped_onions(&mut self) -> Result<(), Error> {
+        let service_ids = match self.dropped_onions.lock() {
+            Ok(mut service_ids) => service_ids.split_off(0),
+            Err(_) => return Ok(()),
+        };
+        for service_id in service_ids {
+            self.del_onion_if_exists(service_id)?;
+        }
+        Ok(())
+    }
+
+    // GETINFO onions/current
+    // Ephemeral onion services created by this control connection.
+    pub fn get_onions_current(&mut self) -> Result<Vec<ServiceID>, Error> {
+        self.get_onions("onions/current")
+    }
+
+    // GETINFO onions/detached
+    // Ephemeral onion services created with the Detach flag, by any control connection.
+    pub fn get_onions_detached(&mut self) -> Result<Vec<ServiceID>, Error> {
+        self.get_onions("onions/detached")
+    }
+
+    fn get_onions(&mut self, info_key: &str) -> Result<Vec<ServiceID>, Error> {
+        match self.cmd_getinfo(info_key) {
+            Ok(service_ids) => parse_service_ids(&service_ids),
+            // Tor answers 551 when there is no service of the requested type
+            Err(Error::Reply(ReplyError { code: ReplyCode::InternalError, ref line }))
+                if line.starts_with("No onion services of the specified type") => Ok(vec![]),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Deletes the detached onion services, for example the ones left by a previous run that
+    // can't be reclaimed, returning their ids.
+    pub fn delete_detached_onions(&mut self) -> Result<Vec<ServiceID>, Error> {
+        let service_ids = self.get_onions_detached()?;
+        for service_id in &service_ids {
+            self.cmd_del_onion(service_id.clone())?;
+        }
+        Ok(service_ids)
     }
 
     // SAVECONF
     pub fn cmd_saveconf(&mut self, force: bool) -> Result<(), Error> {
-        self.raw_cmd(&format!("SAVECONF{}", if force {" FORCE"} else {""})).map(|_|())
+        let mut req = CommandBuilder::new("SAVECONF");
+        if force {
+            req.arg("FORCE");
+        }
+        self.raw_cmd(&req.build()?).map(|_|())
     }
 
     // GETCONF
     pub fn cmd_getconf(&mut self, args: &[&str]) -> Result<HashMap<String, Vec<String>>, Error> {
-        let mut req = String::from("GETCONF");
+        let mut req = CommandBuilder::new("GETCONF");
         let mut res: HashMap<String, Vec<String>> = HashMap::new();
 
         for arg in args.iter() {
-            req.push_str(&format!(" {}", arg));
+            req.arg(arg);
         }
 
-        for line in self.raw_cmd(&req)?.lines.iter() {
-            let key_value_parts: Vec<&str> = line.reply.splitn(2, '=').collect();
-            let key = key_value_parts[0].to_string();
-
-            let mut value: Vec<String> = match res.get(&key) {
-                Some(val) => val.to_owned(),
-                None => Vec::new()
-            };
-
-            if key_value_parts.len() > 1 {
-                value.push(key_value_parts[1].to_string());
-            }
-
-            res.insert(key, value);
+        for line in self.raw_cmd(&req.build()?)?.lines.iter() {
+            let (key, value) = ReplyArgs::key_value(&line.reply)?;
+            res.entry(key).or_default().extend(value);
         }
 
         return Ok(res);
     }
 
+    // GETCONF, keeping the order of the values.  This matters for options like
+    // HiddenServiceOptions, where each HiddenServiceDir starts a new group of options.  Options
+    // without value are returned with an empty one.
+    pub(crate) fn cmd_getconf_list(&mut self,
+                                   args: &[&str])
+                                   -> Result<Vec<(String, String)>, Error> {
+        let mut req = CommandBuilder::new("GETCONF");
+        for arg in args.iter() {
+            req.arg(arg);
+        }
+        self.raw_cmd(&req.build()?)?
+            .lines
+            .iter()
+            .map(|line| {
+                let (key, value) = ReplyArgs::key_value(&line.reply)?;
+                Ok((key, value.unwrap_or_default()))
+            })
+            .collect()
+    }
+
     // SETCONF
     pub fn cmd_setconf(&mut self, args: &[(&str, &str)]) -> Result<(), Error> {
         self.cmd_key_val_list("SETCONF", args)
@@ -717,24 +2143,24 @@ impl<T: Read + Write> Controller<T> {
 
     // LOADCONF
     pub fn cmd_loadconf(&mut self, conf: &str) -> Result<(), Error> {
-        self.raw_cmd(&format!("+LOADCONF\r\n{}\r\n.", conf)).map(|_|())
+        self.raw_cmd(&CommandBuilder::new("LOADCONF").data(conf).build()?).map(|_|())
     }
 
     fn cmd_key_val_list(&mut self, cmd: &str, args: &[(&str, &str)]) -> Result<(), Error> {
-        let mut req = String::from(cmd);
+        let mut req = CommandBuilder::new(cmd);
         for (key, val) in args {
-            req.push_str(&format!(" {}={}", key, val));
+            req.key_value(key, val);
         }
-        return self.raw_cmd(&req).map(|_|())
+        return self.raw_cmd(&req.build()?).map(|_|())
     }
 
     // MAPADDRESS
     pub fn cmd_mapaddress(&mut self, vars: &[(&IpAddr, &str)]) -> Result<(), Error> {
-        let mut req = String::from("MAPADDRESS");
+        let mut req = CommandBuilder::new("MAPADDRESS");
         for (key, val) in vars {
-            req.push_str(&format!(" {}={}", key, val));
+            req.key_value(&key.to_string(), val);
         }
-        self.raw_cmd(&req).map(|_|())
+        self.raw_cmd(&req.build()?).map(|_|())
     }
 
     // TAKEOWNERSHIP
@@ -753,18 +2179,427 @@ impl<T: Read + Write> Controller<T> {
     }
 
     // SETEVENTS
+    pub fn cmd_setevents(&mut self, events: &[&str]) -> Result<(), Error> {
+        let mut req = CommandBuilder::new("SETEVENTS");
+        for event in events.iter() {
+            req.arg(event);
+        }
+        self.raw_cmd(&req.build()?)?;
+        self.subscribed = events.iter().map(|event| event.to_string()).collect();
+        self.drop_unsubscribed_events();
+        Ok(())
+    }
+
+    // Enables the given events on top of the ones already enabled.
+    pub fn add_events(&mut self, events: &[&str]) -> Result<(), Error> {
+        let mut subscribed = self.subscribed.clone();
+        subscribed.extend(events.iter().map(|event| event.to_string()));
+        self.set_subscribed(subscribed)
+    }
+
+    // Disables the given events, unless they have been added more times than removed.
+    pub fn remove_events(&mut self, events: &[&str]) -> Result<(), Error> {
+        let mut subscribed = self.subscribed.clone();
+        for event in events.iter() {
+            if let Some(pos) = subscribed.iter().position(|e| e == event) {
+                subscribed.remove(pos);
+            }
+        }
+        self.set_subscribed(subscribed)
+    }
+
+    fn set_subscribed(&mut self, subscribed: Vec<String>) -> Result<(), Error> {
+        let mut req = CommandBuilder::new("SETEVENTS");
+        let mut sent: Vec<&str> = Vec::new();
+        for event in subscribed.iter() {
+            if !sent.contains(&event.as_str()) {
+                req.arg(event);
+                sent.push(event);
+            }
+        }
+        self.raw_cmd(&req.build()?)?;
+        self.subscribed = subscribed;
+        self.drop_unsubscribed_events();
+        Ok(())
+    }
+
+    // Returns the oldest event already received, without reading from the stream.
+    pub fn pop_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    fn queue_event(&mut self, event: Event) {
+        if self.events.len() >= MAX_QUEUED_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    // Events received before SETEVENTS disabled them would otherwise be kept forever, and be
+    // taken by a later helper as a reply to its own command.
+    fn drop_unsubscribed_events(&mut self) {
+        let subscribed = &self.subscribed;
+        self.events.retain(|event| subscribed.contains(&event.keyword));
+    }
+
     // SIGNAL
+    pub fn cmd_signal(&mut self, signal: Signal) -> Result<(), Error> {
+        self.raw_cmd(&CommandBuilder::new("SIGNAL").arg(signal).build()?).map(|_|())
+    }
+
     // EXTENDCIRCUIT
+    // Extends the circuit circ_id through the relays in path, or builds a new circuit if circ_id
+    // is None.  Returns the id of the extended circuit.
+    pub fn cmd_extendcircuit(&mut self,
+                             circ_id: Option<&CircuitId>,
+                             path: &[&str],
+                             purpose: Option<CircuitPurpose>)
+                             -> Result<CircuitId, Error> {
+        let mut req = CommandBuilder::new("EXTENDCIRCUIT");
+        req.arg(circ_id.map_or("0", |id| id.as_ref()));
+        if !path.is_empty() {
+            req.arg(path.join(","));
+        }
+        if let Some(purpose) = purpose {
+            req.key_value("purpose", purpose);
+        }
+        let reply = self.raw_cmd(&req.build()?)?;
+        // "EXTENDED CircuitID"
+        let args = ReplyArgs::parse(&reply.line(0)?.reply)?;
+        match (args.arg(0), args.arg(1)) {
+            (Some("EXTENDED"), Some(circ_id)) if circ_id.parse::<u32>().is_ok() => {
+                Ok(CircuitId::from(circ_id))
+            }
+            _ => Err(Error::ParseReply(ParseReplyError::MissingField)),
+        }
+    }
+
     // SETCIRCUITPURPOSE
+    pub fn cmd_setcircuitpurpose(&mut self,
+                                 circ_id: &CircuitId,
+                                 purpose: CircuitPurpose)
+                                 -> Result<(), Error> {
+        let req = CommandBuilder::new("SETCIRCUITPURPOSE")
+            .arg(circ_id)
+            .key_value("purpose", purpose)
+            .build()?;
+        self.raw_cmd(&req).map(|_|())
+    }
+
+    // CLOSECIRCUIT
+    // With if_unused, tor only closes the circuit if no stream is attached to it.
+    pub fn cmd_closecircuit(&mut self, circ_id: &CircuitId, if_unused: bool) -> Result<(), Error> {
+        let mut req = CommandBuilder::new("CLOSECIRCUIT");
+        req.arg(circ_id);
+        if if_unused {
+            req.arg("IfUnused");
+        }
+        self.raw_cmd(&req.build()?).map(|_|())
+    }
+
     // ATTACHSTREAM
+    // Attaches the stream to circ_id, or lets tor choose a circuit if circ_id is None.  With hop,
+    // the stream exits at that hop of the circuit instead of the last one.
+    pub fn cmd_attachstream(&mut self,
+                            stream_id: &StreamId,
+                            circ_id: Option<&CircuitId>,
+                            hop: Option<u8>)
+                            -> Result<(), Error> {
+        let mut req = CommandBuilder::new("ATTACHSTREAM");
+        req.arg(stream_id).arg(circ_id.map_or("0", |id| id.as_ref()));
+        if let Some(hop) = hop {
+            req.key_value("HOP", hop);
+        }
+        self.raw_cmd(&req.build()?).map(|_|())
+    }
+
     // POSTDESCRIPTOR
+
     // REDIRECTSTREAM
+    pub fn cmd_redirectstream(&mut self,
+                              stream_id: &StreamId,
+                              address: &str,
+                              port: Option<u16>)
+                              -> Result<(), Error> {
+        let mut req = CommandBuilder::new("REDIRECTSTREAM");
+        req.arg(stream_id).arg(address);
+        if let Some(port) = port {
+            req.arg(port);
+        }
+        self.raw_cmd(&req.build()?).map(|_|())
+    }
+
     // CLOSESTREAM
-    // CLOSECIRCUIT
+    pub fn cmd_closestream(&mut self,
+                           stream_id: &StreamId,
+                           reason: StreamReason)
+                           -> Result<(), Error> {
+        let code = match reason.code() {
+            Some(code) => code,
+            None => {
+                return Err(Error::InvalidArgument(format!("stream reason {} has no code", reason)))
+            }
+        };
+        let req = CommandBuilder::new("CLOSESTREAM").arg(stream_id).arg(code).build()?;
+        self.raw_cmd(&req).map(|_|())
+    }
+
     // USEFEATURE
+
     // RESOLVE
+    // Asks tor to resolve the addresses, the results are reported in ADDRMAP events.  With
+    // reverse, the addresses are IPs to look up the hostname of.
+    pub fn cmd_resolve(&mut self, addresses: &[&str], reverse: bool) -> Result<(), Error> {
+        let mut req = CommandBuilder::new("RESOLVE");
+        if reverse {
+            req.key_value("mode", "reverse");
+        }
+        for address in addresses.iter() {
+            req.arg(address);
+        }
+        self.raw_cmd(&req.build()?).map(|_|())
+    }
+
     // HSFETCH
+    // Asks tor to fetch the descriptor of the onion service, from the given HSDirs or from the
+    // ones tor chooses if servers is empty.  The results are reported in HS_DESC and
+    // HS_DESC_CONTENT events.
+    pub fn cmd_hsfetch(&mut self, address: &str, servers: &[&str]) -> Result<(), Error> {
+        let mut req = CommandBuilder::new("HSFETCH");
+        req.arg(strip_onion_suffix(address));
+        for server in servers.iter() {
+            req.key_value("SERVER", server);
+        }
+        self.raw_cmd(&req.build()?).map(|_|())
+    }
+
     // HSPOST
+    // Uploads the descriptor to the given HSDirs, or to the responsible ones if servers is empty.
+    // The address is required for v3 descriptors.  The results are reported in HS_DESC events.
+    pub fn cmd_hspost(&mut self,
+                      descriptor: &str,
+                      address: Option<&str>,
+                      servers: &[&str])
+                      -> Result<(), Error> {
+        let mut req = CommandBuilder::new("HSPOST");
+        for server in servers.iter() {
+            req.key_value("SERVER", server);
+        }
+        if let Some(address) = address {
+            req.key_value("HSADDRESS", strip_onion_suffix(address));
+        }
+        req.data(descriptor);
+        self.raw_cmd(&req.build()?).map(|_|())
+    }
+}
+
+impl<T: Read + Write + ReadTimeout> Controller<T> {
+    // Waits up to timeout for the next event.  Returns None if no event arrived in time.
+    pub fn wait_event(&mut self, timeout: Duration) -> Result<Option<Event>, Error> {
+        self.wait_event_matching(timeout, |_| true)
+    }
+
+    // Waits up to timeout for an event accepted by pred.  Events that are not accepted are kept
+    // so that they can be retrieved later.
+    pub fn wait_event_matching<F>(&mut self, timeout: Duration, mut pred: F)
+                                  -> Result<Option<Event>, Error>
+        where F: FnMut(&Event) -> bool
+    {
+        if let Some(pos) = self.events.iter().position(&mut pred) {
+            return Ok(self.events.remove(pos));
+        }
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            // The timeout stays set until the whole event has been read, so that an event cut
+            // short by tor can't block us past the deadline.
+            ReadTimeout::set_read_timeout(&self.con.raw_stream, Some(deadline - now))?;
+            let res = self.read_timed_event();
+            ReadTimeout::set_read_timeout(&self.con.raw_stream, None)?;
+            let event = match res? {
+                Some(event) => event,
+                None => return Ok(None),
+            };
+            if pred(&event) {
+                return Ok(Some(event));
+            }
+            self.queue_event(event);
+        }
+    }
+
+    // Resolves the address through tor, waiting up to timeout for the result.  With reverse, the
+    // address is an IP and its hostname is returned.
+    pub fn resolve(&mut self,
+                   address: &str,
+                   reverse: bool,
+                   timeout: Duration)
+                   -> Result<String, Error> {
+        self.add_events(&["ADDRMAP"])?;
+        let res = self.resolve_address(address, reverse, timeout);
+        self.remove_events(&["ADDRMAP"])?;
+        res
+    }
+
+    fn resolve_address(&mut self,
+                       address: &str,
+                       reverse: bool,
+                       timeout: Duration)
+                       -> Result<String, Error> {
+        // Results of earlier lookups or mappings of the same address are not the answer
+        self.events.retain(|event| !is_addrmap_for(event, address, reverse));
+        self.cmd_resolve(&[address], reverse)?;
+        let event = self.wait_event_matching(timeout, |event| {
+                is_addrmap_for(event, address, reverse)
+            })?;
+        let addrmap = match event {
+            Some(event) => AddrMapEvent::parse(&event)?,
+            None => return Err(Error::Resolve(ResolveError::Timeout)),
+        };
+        match addrmap.new_address {
+            Some(new_address) => Ok(new_address),
+            None => Err(Error::Resolve(ResolveError::Failed(addrmap.error))),
+        }
+    }
+
+    // Fetches the descriptor of the onion service, waiting up to timeout for it.
+    pub fn hs_fetch(&mut self,
+                    address: &str,
+                    servers: &[&str],
+                    timeout: Duration)
+                    -> Result<String, Error> {
+        self.add_events(&["HS_DESC", "HS_DESC_CONTENT"])?;
+        let res = self.hs_fetch_descriptor(strip_onion_suffix(address), servers, timeout);
+        self.remove_events(&["HS_DESC", "HS_DESC_CONTENT"])?;
+        res
+    }
+
+    fn hs_fetch_descriptor(&mut self,
+                           address: &str,
+                           servers: &[&str],
+                           timeout: Duration)
+                           -> Result<String, Error> {
+        let deadline = Instant::now() + timeout;
+        // Results of earlier fetches of the same descriptor are not the answer
+        self.events.retain(|event| !is_hs_desc_for(event, address));
+        self.cmd_hsfetch(address, servers)?;
+
+        let mut requested = 0;
+        let mut failed = 0;
+        loop {
+            let now = Instant::now();
+            let event = if now < deadline {
+                self.wait_event_matching(deadline - now, |event| is_hs_desc_for(event, address))?
+            } else {
+                None
+            };
+            let event = match event {
+                Some(event) => event,
+                None => return Err(Error::HsDesc(HsDescError::Timeout)),
+            };
+            if event.keyword == "HS_DESC_CONTENT" {
+                // Failed fetches are also reported with an empty descriptor
+                let content = HsDescContentEvent::parse(&event)?;
+                if !content.descriptor.is_empty() {
+                    return Ok(content.descriptor);
+                }
+                continue;
+            }
+            let hs_desc = HsDescEvent::parse(&event)?;
+            match hs_desc.action {
+                HsDescAction::Requested => requested += 1,
+                HsDescAction::Failed => {
+                    failed += 1;
+                    // A failure reported before any request can't be for all of them
+                    if requested > 0 && failed >= requested {
+                        return Err(Error::HsDesc(HsDescError::Failed(hs_desc.reason)));
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    // Uploads the v3 descriptor of the onion service at address, waiting up to timeout for the
+    // HSDirs to report the results.
+    pub fn hs_post(&mut self,
+                   descriptor: &str,
+                   address: &str,
+                   servers: &[&str],
+                   timeout: Duration)
+                   -> Result<HsPostResult, Error> {
+        self.add_events(&["HS_DESC"])?;
+        let address = strip_onion_suffix(address);
+        let res = self.hs_post_descriptor(descriptor, address, servers, timeout);
+        self.remove_events(&["HS_DESC"])?;
+        res
+    }
+
+    fn hs_post_descriptor(&mut self,
+                          descriptor: &str,
+                          address: &str,
+                          servers: &[&str],
+                          timeout: Duration)
+                          -> Result<HsPostResult, Error> {
+        let deadline = Instant::now() + timeout;
+        self.cmd_hspost(descriptor, Some(address), servers)?;
+
+        let mut res = HsPostResult::default();
+        loop {
+            let now = Instant::now();
+            let event = if now < deadline {
+                self.wait_event_matching(deadline - now, |event| {
+                        event.keyword == "HS_DESC" &&
+                        event.args().split(' ').nth(1) == Some(address)
+                    })?
+            } else {
+                None
+            };
+            let hs_desc = match event {
+                Some(event) => HsDescEvent::parse(&event)?,
+                None if res.uploaded.is_empty() && res.failed.is_empty() &&
+                        res.pending.is_empty() => {
+                    return Err(Error::HsDesc(HsDescError::Timeout))
+                }
+                None => return Ok(res),
+            };
+            match hs_desc.action {
+                HsDescAction::Upload => res.pending.push(hs_desc.hs_dir),
+                HsDescAction::Uploaded => {
+                    res.pending.retain(|hs_dir| *hs_dir != hs_desc.hs_dir);
+                    res.uploaded.push(hs_desc.hs_dir);
+                }
+                HsDescAction::Failed => {
+                    res.pending.retain(|hs_dir| *hs_dir != hs_desc.hs_dir);
+                    res.failed.push((hs_desc.hs_dir, hs_desc.reason));
+                }
+                _ => (),
+            }
+            if res.pending.is_empty() && !(res.uploaded.is_empty() && res.failed.is_empty()) {
+                return Ok(res);
+            }
+        }
+    }
+
+    // Reads the next event once the read timeout is set.  Returns None if nothing arrived before
+    // the timeout; running out of time in the middle of the event is an error.
+    fn read_timed_event(&mut self) -> Result<Option<Event>, Error> {
+        if self.con.buf_reader.buffer().is_empty() {
+            match self.con.buf_reader.fill_buf() {
+                Ok(_) => (),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock ||
+                                err.kind() == io::ErrorKind::TimedOut => return Ok(None),
+                Err(err) => return Err(Error::Stream(err)),
+            }
+        }
+        let (status_code, reply_lines) = self.read_reply()?;
+        if status_code != 650 {
+            return Err(Error::RawReply(RawReplyError::UnexpectedReply));
+        }
+        Event::from_lines(reply_lines).map(Some)
+    }
 }
 
 impl<T: Read + Write> Drop for Controller<T> {
@@ -773,3 +2608,562 @@ impl<T: Read + Write> Drop for Controller<T> {
         self.cmd_quit().unwrap_or(());
     }
 }
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::cell::{Cell, RefCell};
+    use std::collections::VecDeque;
+    use std::io;
+    use std::io::{BufReader, BufWriter, Read, Write};
+    use std::path::PathBuf;
+    use std::rc::Rc;
+    use std::time::Duration;
+    use super::{AddOnion, AuthMethod, CommandBuilder, Connection, Controller, Error, Event,
+                HsDescError, HsDescReason, IntroDosDefense, KeyType, OnionClientAuth,
+                OnionClientAuthCredential, OnionClientAuthV3, OnionError, OnionFlags, OnionKey,
+                OnionTarget, PowDefense, ProtocolInfo, ReadTimeout, Reply, ReplyArgs, ReplyCode,
+                ReplyLine, ReplyStatus, Signal, MAX_QUEUED_EVENTS, is_addrmap_for, is_single_cmd,
+                parse_service_ids, quote, quote_if_needed, read_quoted, unquote};
+
+    // Stands in for tor: flushing a command releases the next scripted reply, and reading past
+    // the released data times out when a read timeout is set, or is the end of the stream.
+    #[derive(Clone, Default)]
+    pub(crate) struct MockStream {
+        input: Rc<RefCell<VecDeque<u8>>>,
+        replies: Rc<RefCell<VecDeque<String>>>,
+        output: Rc<RefCell<Vec<u8>>>,
+        timeout: Rc<Cell<Option<Duration>>>,
+    }
+
+    impl MockStream {
+        // Lines sent to tor so far
+        pub(crate) fn sent(&self) -> Vec<String> {
+            String::from_utf8(self.output.borrow().clone())
+                .unwrap()
+                .split("\r\n")
+                .filter(|line| !line.is_empty())
+                .map(|line| line.to_string())
+                .collect()
+        }
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut input = self.input.borrow_mut();
+            if input.is_empty() && self.timeout.get().is_some() {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "timed out"));
+            }
+            let len = buf.len().min(input.len());
+            for (byte, input_byte) in buf.iter_mut().zip(input.drain(..len)) {
+                *byte = input_byte;
+            }
+            Ok(len)
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            if let Some(reply) = self.replies.borrow_mut().pop_front() {
+                self.input.borrow_mut().extend(reply.bytes());
+            }
+            Ok(())
+        }
+    }
+
+    impl ReadTimeout for MockStream {
+        fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+            self.timeout.set(dur);
+            Ok(())
+        }
+    }
+
+    // Controller whose n-th command gets replies[n], which may also carry events
+    pub(crate) fn mock_controller(replies: &[&str]) -> (Controller<MockStream>, MockStream) {
+        let stream = MockStream::default();
+        stream.replies.borrow_mut().extend(replies.iter().map(|reply| reply.to_string()));
+        let con = Connection {
+            raw_stream: stream.clone(),
+            buf_reader: BufReader::new(stream.clone()),
+            buf_writer: BufWriter::new(stream.clone()),
+        };
+        (Controller::new(con), stream)
+    }
+
+    // Event as received in a single line reply
+    pub(crate) fn event(line: &str) -> Event {
+        Event::from_lines(vec![ReplyLine { reply: line.to_string(), data: None }]).unwrap()
+    }
+
+    #[test]
+    fn test_events_during_reply() {
+        let (mut controller, stream) =
+            mock_controller(&["650 CIRC 1 LAUNCHED\r\n250 OK\r\n",
+                              "650 CIRC 1 BUILT\r\n250-version=0.4.8.9\r\n250 OK\r\n",
+                              "250 OK\r\n650-CIRC 2 LAUNCHED\r\n"]);
+        controller.add_events(&["CIRC"]).unwrap();
+        assert_eq!(controller.get_version().unwrap(), "0.4.8.9");
+        assert_eq!(controller.pop_event().unwrap().args(), "1 LAUNCHED");
+        assert_eq!(controller.pop_event().unwrap().args(), "1 BUILT");
+        assert!(controller.pop_event().is_none());
+        assert!(controller.wait_event(Duration::from_millis(10)).unwrap().is_none());
+
+        // An event cut short by the timeout leaves the stream out of sync
+        controller.cmd_signal(Signal::Debug).unwrap();
+        assert!(controller.wait_event(Duration::from_millis(10)).is_err());
+        assert_eq!(stream.sent(),
+                   ["SETEVENTS CIRC", "GETINFO version", "SIGNAL DEBUG"]);
+    }
+
+    #[test]
+    fn test_event_subscriptions() {
+        let (mut controller, stream) =
+            mock_controller(&["250 OK\r\n",
+                              "250 OK\r\n",
+                              "650 CIRC 1 BUILT\r\n650 STREAM 1 NEW 0 example.com:80\r\n\
+                               250 OK\r\n",
+                              "250 OK\r\n"]);
+        controller.add_events(&["CIRC"]).unwrap();
+        controller.add_events(&["CIRC", "STREAM"]).unwrap();
+        controller.remove_events(&["CIRC"]).unwrap();
+        assert_eq!(controller.events.len(), 2);
+        controller.remove_events(&["CIRC"]).unwrap();
+        assert_eq!(controller.pop_event().unwrap().keyword, "STREAM");
+        assert!(controller.pop_event().is_none());
+        assert_eq!(stream.sent(),
+                   ["SETEVENTS CIRC",
+                    "SETEVENTS CIRC STREAM",
+                    "SETEVENTS CIRC STREAM",
+                    "SETEVENTS STREAM"]);
+    }
+
+    #[test]
+    fn test_max_queued_events() {
+        let mut reply = String::new();
+        for n in 0..MAX_QUEUED_EVENTS + 5 {
+            reply.push_str(&format!("650 CIRC {} BUILT\r\n", n));
+        }
+        reply.push_str("250 OK\r\n");
+        let (mut controller, _) = mock_controller(&[&reply]);
+        controller.add_events(&["CIRC"]).unwrap();
+        assert_eq!(controller.events.len(), MAX_QUEUED_EVENTS);
+        assert_eq!(controller.pop_event().unwrap().args(), "5 BUILT");
+    }
+
+    #[test]
+    fn test_add_onion() {
+        let mut add_onion = AddOnion {
+            key: OnionKey::New(KeyType::Ed25519V3),
+            flags: vec![OnionFlags::Detach, OnionFlags::MaxStreamsCloseCircuit],
+            max_streams: Some(10),
+            ports: vec![(80, Some(OnionTarget::Port(8080))), (443, None)],
+            client_auths: vec![],
+            client_auths_v3: vec![],
+            pow_defense: PowDefense { enabled: true, queue_rate: Some(100), queue_burst: None },
+        };
+        add_onion.validate().unwrap();
+        assert_eq!(add_onion.to_string(),
+                   "ADD_ONION NEW:ED25519-V3 Flags=Detach,MaxStreamsCloseCircuit MaxStreams=10 \
+                    PoWDefensesEnabled=1 PoWQueueRate=100 Port=80,8080 Port=443");
+
+        // Tor accepts MaxStreamsCloseCircuit without MaxStreams
+        add_onion.max_streams = None;
+        add_onion.validate().unwrap();
+        add_onion.flags = vec![];
+        add_onion.client_auths_v3.push(OnionClientAuthV3 { public_key: [0; 32] });
+        match add_onion.validate() {
+            Err(OnionError::ClientAuthWithoutFlag(OnionFlags::V3Auth)) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+        add_onion.flags.push(OnionFlags::V3Auth);
+        add_onion.validate().unwrap();
+        add_onion.key = OnionKey::New(KeyType::Rsa1024);
+        match add_onion.validate() {
+            Err(OnionError::KeyTypeMismatch) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        add_onion.flags = vec![OnionFlags::BasicAuth];
+        add_onion.client_auths_v3 = vec![];
+        add_onion.pow_defense = PowDefense::default();
+        add_onion.client_auths = vec![OnionClientAuth {
+                                          client_name: "bob+1_x-y".to_string(),
+                                          client_blob: None,
+                                      }];
+        add_onion.validate().unwrap();
+        assert_eq!(add_onion.client_auths[0].to_string(), "ClientAuth=bob+1_x-y");
+        add_onion.client_auths[0].client_blob = Some("YmxvYg==".to_string());
+        assert_eq!(add_onion.client_auths[0].to_string(), "ClientAuth=bob+1_x-y:YmxvYg==");
+        add_onion.client_auths[0].client_name = "bob smith".to_string();
+        match add_onion.validate() {
+            Err(OnionError::InvalidClientName(_)) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_dos_defenses() {
+        let mut add_onion = AddOnion {
+            key: OnionKey::New(KeyType::Ed25519V3),
+            flags: vec![],
+            max_streams: None,
+            ports: vec![(80, None)],
+            client_auths: vec![],
+            client_auths_v3: vec![],
+            pow_defense: PowDefense { enabled: true, queue_rate: Some(10), queue_burst: Some(20) },
+        };
+        add_onion.validate().unwrap();
+        assert_eq!(add_onion.to_string(),
+                   "ADD_ONION NEW:ED25519-V3 PoWDefensesEnabled=1 PoWQueueRate=10 \
+                    PoWQueueBurst=20 Port=80");
+        add_onion.pow_defense.queue_burst = Some(5);
+        match add_onion.validate() {
+            Err(OnionError::InvalidDefense(ref option)) => {
+                assert_eq!(option, "HiddenServicePoWQueueBurst")
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+        // Tor only checks the burst against the rate when the defense is enabled
+        add_onion.pow_defense.enabled = false;
+        add_onion.validate().unwrap();
+        add_onion.pow_defense.queue_rate = Some(u32::MAX);
+        assert!(add_onion.validate().is_err());
+
+        let mut intro_dos = IntroDosDefense { enabled: true, ..Default::default() };
+        intro_dos.validate().unwrap();
+        intro_dos.rate_per_sec = Some(300);
+        assert!(intro_dos.validate().is_err());
+        intro_dos.burst_per_sec = Some(300);
+        intro_dos.validate().unwrap();
+        intro_dos.burst_per_sec = Some(i32::MAX as u32 + 1);
+        assert!(intro_dos.validate().is_err());
+    }
+
+    #[test]
+    fn test_add_onion_targets() {
+        let add_onion = AddOnion {
+            key: OnionKey::New(KeyType::Best),
+            flags: vec![],
+            max_streams: None,
+            ports: vec![(80, Some(OnionTarget::Addr("192.168.1.5:8080".parse().unwrap()))),
+                        (81, Some(OnionTarget::Addr("[::1]:8081".parse().unwrap()))),
+                        (82, Some(OnionTarget::Unix(PathBuf::from("/run/app.sock")))),
+                        (83, Some(OnionTarget::Unix(PathBuf::from("/run/my app/\"x\".sock"))))],
+            client_auths: vec![],
+            client_auths_v3: vec![],
+            pow_defense: PowDefense::default(),
+        };
+        add_onion.validate().unwrap();
+        assert_eq!(add_onion.to_string(),
+                   "ADD_ONION NEW:BEST Port=80,192.168.1.5:8080 Port=81,[::1]:8081 \
+                    Port=82,unix:/run/app.sock \
+                    Port=\"83,unix:\\\"/run/my app/\\\\\\\"x\\\\\\\".sock\\\"\"");
+
+        let mut add_onion = add_onion;
+        add_onion.ports = vec![(80, Some(OnionTarget::Unix(PathBuf::from("/run/a,b.sock"))))];
+        match add_onion.validate() {
+            Err(OnionError::InvalidTarget) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_onion_client_auth_credential() {
+        let line = "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd \
+                    x25519:yPGUxgKaC5ACyEzsdANHJEJzt5DIqDRBlAFaAWWQn0o= \
+                    ClientName=alice Flags=Permanent";
+        let credential = OnionClientAuthCredential::parse(line).unwrap();
+        assert_eq!(credential.service_id.as_ref(),
+                   "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd");
+        assert_eq!(credential.client_name, Some("alice".to_string()));
+        assert!(credential.permanent);
+        assert_eq!(credential.command().build().unwrap(),
+                   format!("ONION_CLIENT_AUTH_ADD {}", line));
+
+        let address = "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd";
+        let key = "yPGUxgKaC5ACyEzsdANHJEJzt5DIqDRBlAFaAWWQn0o=";
+        let credential = OnionClientAuthCredential::parse(&format!("{} x25519:{}", address, key))
+            .unwrap();
+        assert_eq!(credential.client_name, None);
+        assert!(!credential.permanent);
+        assert!(OnionClientAuthCredential::parse(&format!("{} x448:{}", address, key)).is_err());
+    }
+
+    #[test]
+    fn test_parse_service_ids() {
+        let data = "25njqamcweflpvkl73j4szahhihoc4xt3ktcgjnpaingr5yhkenl5sid\nexpyuzz4wqqyqhjn\n";
+        let service_ids = parse_service_ids(data).unwrap();
+        assert_eq!(service_ids.len(), 2);
+        assert_eq!(service_ids[1].as_ref(), "expyuzz4wqqyqhjn");
+        assert!(parse_service_ids("").unwrap().is_empty());
+        assert!(parse_service_ids("not an onion").is_err());
+    }
+
+    #[test]
+    fn test_get_onions() {
+        let (mut controller, _) =
+            mock_controller(&["551 No onion services of the specified type.\r\n",
+                              "250+onions/current=\r\n\
+                               pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd\r\n\
+                               .\r\n250 OK\r\n",
+                              "551 Internal error\r\n"]);
+        assert!(controller.get_onions_detached().unwrap().is_empty());
+        assert_eq!(controller.get_onions_current().unwrap().len(), 1);
+        match controller.get_onions_current() {
+            Err(Error::Reply(ref err)) if err.code == ReplyCode::InternalError => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_is_addrmap_for() {
+        let forward = event("ADDRMAP Example.com 93.184.216.34 \"2017-06-12 16:30:00\"");
+        assert!(is_addrmap_for(&forward, "example.com", false));
+        assert!(!is_addrmap_for(&forward, "example.org", false));
+        let reverse = event("ADDRMAP 93.184.216.34 example.com \"2017-06-12 16:30:00\" \
+                             EXPIRES=\"2017-06-12 14:30:00\" CACHED=\"NO\" STREAMID=42");
+        assert!(is_addrmap_for(&reverse, "93.184.216.34", true));
+        let cached = event("ADDRMAP REVERSE[93.184.216.34] example.com \"2017-06-12 16:30:00\"");
+        assert!(is_addrmap_for(&cached, "93.184.216.34", true));
+        assert!(!is_addrmap_for(&cached, "93.184.216.34", false));
+        let circ = event("CIRC 93.184.216.34 BUILT");
+        assert!(!is_addrmap_for(&circ, "93.184.216.34", true));
+    }
+
+    #[test]
+    fn test_add_onion_non_anonymous() {
+        let (mut controller, stream) =
+            mock_controller(&["250-HiddenServiceSingleHopMode=0\r\n\
+                               250 HiddenServiceNonAnonymousMode=0\r\n"]);
+        let add_onion = AddOnion {
+            key: OnionKey::New(KeyType::Ed25519V3),
+            flags: vec![OnionFlags::NonAnonymous],
+            max_streams: None,
+            ports: vec![(80, None)],
+            client_auths: vec![],
+            client_auths_v3: vec![],
+            pow_defense: PowDefense::default(),
+        };
+        match controller.cmd_add_onion(add_onion) {
+            Err(Error::Onion(OnionError::NonAnonymousNotConfigured)) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert_eq!(stream.sent(),
+                   ["GETCONF HiddenServiceSingleHopMode HiddenServiceNonAnonymousMode"]);
+    }
+
+    #[test]
+    fn test_hs_fetch() {
+        let address = "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd";
+        let hs_desc = |action: &str, hs_dir: &str| {
+            format!("650 HS_DESC {} {} NO_AUTH {} REASON=NOT_FOUND\r\n", action, address, hs_dir)
+        };
+        // A failure left from an earlier fetch, then one before any request has been reported
+        let setevents = format!("{}250 OK\r\n", hs_desc("FAILED", "$AAAA"));
+        let hsfetch = format!("250 OK\r\n{}{}650+HS_DESC_CONTENT {} descid $BBBB\r\n\
+                               hs-descriptor 3\r\n.\r\n650 OK\r\n",
+                              hs_desc("FAILED", "$CCCC"),
+                              hs_desc("REQUESTED", "$BBBB"),
+                              address);
+        let (mut controller, stream) =
+            mock_controller(&[&setevents, &hsfetch, "250 OK\r\n"]);
+        let onion = format!("{}.onion", address);
+        let descriptor = controller.hs_fetch(&onion, &[], Duration::from_millis(100)).unwrap();
+        assert_eq!(descriptor, "hs-descriptor 3\n");
+        assert_eq!(stream.sent(),
+                   ["SETEVENTS HS_DESC HS_DESC_CONTENT".to_string(),
+                    format!("HSFETCH {}", address),
+                    "SETEVENTS".to_string()]);
+    }
+
+    #[test]
+    fn test_hs_post() {
+        let address = "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd";
+        let hs_desc = |action: &str, hs_dir: &str, reason: &str| {
+            format!("650 HS_DESC {} {} UNKNOWN {}{}\r\n", action, address, hs_dir, reason)
+        };
+        let hspost = format!("250 OK\r\n{}{}{}{}{}",
+                             hs_desc("UPLOAD", "$AAAA", " descid"),
+                             hs_desc("UPLOAD", "$BBBB", " descid"),
+                             hs_desc("UPLOAD", "$CCCC", " descid"),
+                             hs_desc("UPLOADED", "$AAAA", ""),
+                             hs_desc("FAILED", "$BBBB", " REASON=UPLOAD_REJECTED"));
+        let (mut controller, stream) =
+            mock_controller(&["250 OK\r\n", &hspost, "250 OK\r\n", "250 OK\r\n", "250 OK\r\n",
+                              "250 OK\r\n"]);
+        let timeout = Duration::from_millis(10);
+        let res = controller.hs_post("hs-descriptor 3\n", address, &[], timeout).unwrap();
+        assert_eq!(res.uploaded, ["$AAAA"]);
+        assert_eq!(res.failed, [("$BBBB".to_string(), Some(HsDescReason::UploadRejected))]);
+        assert_eq!(res.pending, ["$CCCC"]);
+        assert_eq!(stream.sent()[1..4],
+                   [format!("+HSPOST HSADDRESS={}", address),
+                    "hs-descriptor 3".to_string(),
+                    ".".to_string()]);
+
+        // No HSDir reported anything
+        match controller.hs_post("hs-descriptor 3\n", address, &[], timeout) {
+            Err(Error::HsDesc(HsDescError::Timeout)) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_protocolinfo() {
+        let lines = ["PROTOCOLINFO 1",
+                     "AUTH METHODS=COOKIE,SAFECOOKIE,FANCYNEW \
+                      COOKIEFILE=\"/run/tor/control.authcookie\"",
+                     "VERSION Tor=\"0.4.8.9\"",
+                     "OK"];
+        let reply = Reply {
+            lines: lines.iter()
+                .map(|line| ReplyLine { reply: line.to_string(), data: None })
+                .collect(),
+        };
+        let protocolinfo = ProtocolInfo::parse(&reply).unwrap();
+        assert_eq!(protocolinfo.auth_methods,
+                   vec![AuthMethod::Cookie,
+                        AuthMethod::SafeCookie,
+                        AuthMethod::Unknown("FANCYNEW".to_string())]);
+        assert_eq!(protocolinfo.cookie_files, vec!["/run/tor/control.authcookie"]);
+        assert_eq!(protocolinfo.tor_ver, "0.4.8.9");
+    }
+
+    #[test]
+    fn test_cmd_protocolinfo() {
+        let (mut controller, _) =
+            mock_controller(&["250-PROTOCOLINFO 1\r\n250-AUTH METHODS=NULL,FANCYNEW\r\n\
+                               250-VERSION Tor=\"0.4.8.9\"\r\n250 OK\r\n",
+                              "250-PROTOCOLINFO 2\r\n250 OK\r\n"]);
+        let protocolinfo = controller.cmd_protocolinfo().unwrap();
+        assert_eq!(protocolinfo.auth_methods,
+                   vec![AuthMethod::Null, AuthMethod::Unknown("FANCYNEW".to_string())]);
+        assert!(protocolinfo.cookie_files.is_empty());
+        assert!(controller.cmd_protocolinfo().is_err());
+    }
+
+    #[test]
+    fn test_reply_code() {
+        for code in [250, 251, 451, 500, 510, 511, 512, 513, 514, 515, 550, 551, 552, 553, 554, 555,
+                     650, 599] {
+            assert_eq!(ReplyCode::from(code).code(), code);
+        }
+        assert_eq!(ReplyCode::from(451), ReplyCode::ResourceExhausted);
+        assert_eq!(ReplyCode::from(599), ReplyCode::Other(599));
+        assert_eq!(ReplyCode::ResourceExhausted.status(), ReplyStatus::TempNegative);
+        assert_eq!(ReplyCode::UnrecognizedEntity.status(), ReplyStatus::PermNegative);
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("/run/tor/control.authcookie"), "\"/run/tor/control.authcookie\"");
+        assert_eq!(quote("a \"b\"\\c\n\x01"), "\"a \\\"b\\\"\\\\c\\n\\001\"");
+        assert_eq!(unquote(&quote("a \"b\"\\c\n\x01é")).unwrap(), "a \"b\"\\c\n\x01é");
+        assert_eq!(unquote("\"C:\\\\tor\\\\cookie\"").unwrap(), "C:\\tor\\cookie");
+        assert_eq!(unquote("\"caf\\303\\251 \\x41\\t\\'\"").unwrap(), "café A\t'");
+        assert_eq!(unquote("unquoted").unwrap(), "unquoted");
+        assert_eq!(quote_if_needed("unquoted"), "unquoted");
+        assert_eq!(quote_if_needed(""), "\"\"");
+        assert_eq!(quote_if_needed("a b"), "\"a b\"");
+        let (first, rest) = read_quoted("\"a\\\"b\" \"c\"").unwrap();
+        assert_eq!((first.as_str(), rest), ("a\"b", " \"c\""));
+        assert!(unquote("\"unterminated").is_err());
+        assert!(unquote("\"trailing\"data").is_err());
+        assert!(unquote("\"bad escape \\q\"").is_err());
+        assert!(unquote("\"\\400\"").is_err());
+    }
+
+    #[test]
+    fn test_command_builder() {
+        let cmd = CommandBuilder::new("SETCONF")
+            .key_value("ContactInfo", "me <me@example.com>")
+            .key_value("SocksPort", 9050)
+            .build()
+            .unwrap();
+        assert_eq!(cmd, "SETCONF ContactInfo=\"me <me@example.com>\" SocksPort=9050");
+        let cmd = CommandBuilder::new("HSPOST").key_value("SERVER", "relay").data("a\n.b").build();
+        assert_eq!(cmd.unwrap(), "+HSPOST SERVER=relay\r\na\r\n..b\r\n.");
+        assert!(CommandBuilder::new("GETINFO").arg("version\r\nSIGNAL HALT").build().is_err());
+        assert!(CommandBuilder::new("GETINFO").arg("version x").build().is_err());
+        assert!(CommandBuilder::new("GETINFO").arg("").build().is_err());
+        assert!(CommandBuilder::new("SETCONF").key_value("Nickname", "a\r\n250 OK").build()
+            .is_err());
+        assert!(CommandBuilder::new("SETCONF").key_value("A=B", "c").build().is_err());
+
+        let cmd = CommandBuilder::new("LOADCONF").data(".\nSIGNAL HALT\n").build().unwrap();
+        assert!(is_single_cmd(&cmd));
+        assert!(is_single_cmd(&CommandBuilder::new("LOADCONF").data("").build().unwrap()));
+        assert!(!is_single_cmd("GETINFO version\r\nSIGNAL HALT"));
+        assert!(!is_single_cmd("GETINFO version\n"));
+        assert!(!is_single_cmd("+X\r\nSIGNAL HALT"));
+        assert!(!is_single_cmd("+X\r\n.\r\nSIGNAL HALT\r\n."));
+        assert!(!is_single_cmd("+X\nSIGNAL HALT\r\n."));
+    }
+
+    #[test]
+    fn test_reply_args() {
+        let args = ReplyArgs::parse("CIRC 7 BUILT $AAAA=relay1,$BBBB~relay2 NCIRCS=3 \
+                                     SUMMARY=\"Done, \\\"really\\\"\" EMPTY= NCIRCS=4")
+            .unwrap();
+        assert_eq!(args.positional(), ["CIRC", "7", "BUILT", "$AAAA=relay1,$BBBB~relay2"]);
+        assert_eq!(args.arg(1), Some("7"));
+        assert_eq!(args.arg(4), None);
+        assert_eq!(args.get::<u32>("NCIRCS").unwrap(), 3);
+        assert_eq!(args.values("NCIRCS"), vec!["3", "4"]);
+        assert_eq!(args.value("SUMMARY"), Some("Done, \"really\""));
+        assert_eq!(args.value("EMPTY"), Some(""));
+        assert_eq!(args.get_opt::<u32>("MISSING").unwrap(), None);
+        assert!(args.get::<u32>("MISSING").is_err());
+        assert!(args.get::<u32>("SUMMARY").is_err());
+
+        let args = ReplyArgs::parse("ADDRMAP example.com 93.184.216.34 \"2017-06-12 16:30:00\"")
+            .unwrap();
+        assert_eq!(args.arg(3), Some("2017-06-12 16:30:00"));
+        assert!(ReplyArgs::parse("VERSION Tor=\"0.4.8.9\"trailing").is_err());
+
+        assert_eq!(ReplyArgs::key_value("HiddenServicePort=80 127.0.0.1:8080").unwrap(),
+                   ("HiddenServicePort".to_string(), Some("80 127.0.0.1:8080".to_string())));
+        assert_eq!(ReplyArgs::key_value("Nickname=\"a \\\"b\\\"\"").unwrap().1,
+                   Some("a \"b\"".to_string()));
+        assert_eq!(ReplyArgs::key_value("HiddenServiceDir").unwrap(),
+                   ("HiddenServiceDir".to_string(), None));
+    }
+
+    #[test]
+    fn test_reply_parsing() {
+        let (mut controller, _) =
+            mock_controller(&["250-HiddenServicePort=80 127.0.0.1:8080\r\n\
+                               250-HiddenServicePort=443\r\n250 ContactInfo\r\n",
+                              "250-ServiceID=sid1\r\n250-PrivateKey=RSA1024:c2stMQ==\r\n\
+                               250-ClientAuth=bob:YmxvYg==\r\n250 OK\r\n",
+                              "250 EXTENDED 12\r\n",
+                              "250 EXTENDED twelve\r\n"]);
+        let conf = controller.cmd_getconf(&["HiddenServicePort", "ContactInfo"]).unwrap();
+        assert_eq!(conf["HiddenServicePort"], ["80 127.0.0.1:8080", "443"]);
+        assert!(conf["ContactInfo"].is_empty());
+
+        let reply = controller.cmd_add_onion(AddOnion {
+                key: OnionKey::New(KeyType::Rsa1024),
+                flags: vec![OnionFlags::BasicAuth],
+                max_streams: None,
+                ports: vec![(80, None)],
+                client_auths: vec![OnionClientAuth {
+                                       client_name: "bob".to_string(),
+                                       client_blob: None,
+                                   }],
+                client_auths_v3: vec![],
+                pow_defense: PowDefense::default(),
+            })
+            .unwrap();
+        assert_eq!(reply.service_id.as_ref(), "sid1");
+        assert_eq!(reply.sk, Some(OnionKey::Rsa1024("c2stMQ==".to_string())));
+        assert_eq!(reply.client_auths, [("bob".to_string(), "YmxvYg==".to_string())]);
+
+        assert_eq!(controller.cmd_extendcircuit(None, &[], None).unwrap().as_ref(), "12");
+        assert!(controller.cmd_extendcircuit(None, &[], None).is_err());
+    }
+}
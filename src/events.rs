@@ -0,0 +1,412 @@
+use std::fmt;
+
+use control::{CircuitId, CircuitReason, Error, Event, ParseReplyError, ReplyArgs, StreamId,
+              StreamReason};
+
+fn check_keyword(event: &Event, keyword: &str) -> Result<(), Error> {
+    if event.keyword != keyword {
+        return Err(Error::ParseReply(ParseReplyError::UnexpectedEvent));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CircStatus {
+    Launched,
+    Built,
+    GuardWait,
+    Extended,
+    Failed,
+    Closed,
+    Unknown(String),
+}
+
+impl From<&str> for CircStatus {
+    fn from(s: &str) -> Self {
+        match s {
+            "LAUNCHED" => CircStatus::Launched,
+            "BUILT" => CircStatus::Built,
+            "GUARD_WAIT" => CircStatus::GuardWait,
+            "EXTENDED" => CircStatus::Extended,
+            "FAILED" => CircStatus::Failed,
+            "CLOSED" => CircStatus::Closed,
+            _ => CircStatus::Unknown(s.to_string()),
+        }
+    }
+}
+
+// Circuit status changed (CIRC event)
+#[derive(Debug, Clone)]
+pub struct CircEvent {
+    pub circ_id: CircuitId,
+    pub status: CircStatus,
+    // Relays in the circuit, as "$fingerprint~nickname" or "$fingerprint"
+    pub path: Vec<String>,
+    pub purpose: Option<String>,
+    pub reason: Option<CircuitReason>,
+    pub remote_reason: Option<CircuitReason>,
+}
+
+impl CircEvent {
+    pub fn parse(event: &Event) -> Result<CircEvent, Error> {
+        check_keyword(event, "CIRC")?;
+        let args = ReplyArgs::parse(event.args())?;
+        let positional = args.positional();
+        if positional.len() < 2 {
+            return Err(Error::ParseReply(ParseReplyError::MissingField));
+        }
+        let path = match args.arg(2) {
+            Some(path) => path.split(',').map(|relay| relay.to_string()).collect(),
+            None => Vec::new(),
+        };
+        Ok(CircEvent {
+            circ_id: CircuitId::from(positional[0].as_str()),
+            status: CircStatus::from(positional[1].as_str()),
+            path,
+            purpose: args.value("PURPOSE").map(|purpose| purpose.to_string()),
+            reason: args.value("REASON").map(CircuitReason::from),
+            remote_reason: args.value("REMOTE_REASON").map(CircuitReason::from),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamStatus {
+    New,
+    NewResolve,
+    Remap,
+    SentConnect,
+    SentResolve,
+    Succeeded,
+    Failed,
+    Closed,
+    Detached,
+    ControllerWait,
+    Unknown(String),
+}
+
+impl From<&str> for StreamStatus {
+    fn from(s: &str) -> Self {
+        match s {
+            "NEW" => StreamStatus::New,
+            "NEWRESOLVE" => StreamStatus::NewResolve,
+            "REMAP" => StreamStatus::Remap,
+            "SENTCONNECT" => StreamStatus::SentConnect,
+            "SENTRESOLVE" => StreamStatus::SentResolve,
+            "SUCCEEDED" => StreamStatus::Succeeded,
+            "FAILED" => StreamStatus::Failed,
+            "CLOSED" => StreamStatus::Closed,
+            "DETACHED" => StreamStatus::Detached,
+            "CONTROLLER_WAIT" => StreamStatus::ControllerWait,
+            _ => StreamStatus::Unknown(s.to_string()),
+        }
+    }
+}
+
+// Stream status changed (STREAM event)
+#[derive(Debug, Clone)]
+pub struct StreamEvent {
+    pub stream_id: StreamId,
+    pub status: StreamStatus,
+    // Circuit the stream is attached to, "0" if it is not attached
+    pub circ_id: CircuitId,
+    // Destination as "address:port"
+    pub target: String,
+    pub reason: Option<StreamReason>,
+    pub remote_reason: Option<StreamReason>,
+    pub source_addr: Option<String>,
+    pub purpose: Option<String>,
+}
+
+impl StreamEvent {
+    pub fn parse(event: &Event) -> Result<StreamEvent, Error> {
+        check_keyword(event, "STREAM")?;
+        let args = ReplyArgs::parse(event.args())?;
+        let positional = args.positional();
+        if positional.len() < 4 {
+            return Err(Error::ParseReply(ParseReplyError::MissingField));
+        }
+        Ok(StreamEvent {
+            stream_id: StreamId::from(positional[0].as_str()),
+            status: StreamStatus::from(positional[1].as_str()),
+            circ_id: CircuitId::from(positional[2].as_str()),
+            target: positional[3].clone(),
+            reason: args.value("REASON").map(StreamReason::from),
+            remote_reason: args.value("REMOTE_REASON").map(StreamReason::from),
+            source_addr: args.value("SOURCE_ADDR").map(|addr| addr.to_string()),
+            purpose: args.value("PURPOSE").map(|purpose| purpose.to_string()),
+        })
+    }
+}
+
+// Address mapping added or resolved (ADDRMAP event)
+#[derive(Debug, Clone)]
+pub struct AddrMapEvent {
+    pub address: String,
+    // None when the mapping failed ("<error>")
+    pub new_address: Option<String>,
+    // Expiry in local time, None if it never expires
+    pub expiry: Option<String>,
+    pub error: Option<String>,
+    // Expiry in UTC
+    pub expires: Option<String>,
+    pub cached: Option<bool>,
+    pub stream_id: Option<StreamId>,
+}
+
+impl AddrMapEvent {
+    pub fn parse(event: &Event) -> Result<AddrMapEvent, Error> {
+        check_keyword(event, "ADDRMAP")?;
+        let args = ReplyArgs::parse(event.args())?;
+        let positional = args.positional();
+        if positional.len() < 3 {
+            return Err(Error::ParseReply(ParseReplyError::MissingField));
+        }
+        let new_address = match positional[1].as_str() {
+            "<error>" => None,
+            new_address => Some(new_address.to_string()),
+        };
+        let expiry = match positional[2].as_str() {
+            "NEVER" => None,
+            expiry => Some(expiry.to_string()),
+        };
+        Ok(AddrMapEvent {
+            address: positional[0].clone(),
+            new_address,
+            expiry,
+            error: args.value("error").map(|error| error.to_string()),
+            expires: args.value("EXPIRES").map(|expires| expires.to_string()),
+            cached: args.value("CACHED").map(|cached| cached == "YES"),
+            stream_id: args.value("STREAMID").map(StreamId::from),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HsDescAction {
+    Requested,
+    Upload,
+    Received,
+    Uploaded,
+    Ignore,
+    Failed,
+    Created,
+    Unknown(String),
+}
+
+impl From<&str> for HsDescAction {
+    fn from(s: &str) -> Self {
+        match s {
+            "REQUESTED" => HsDescAction::Requested,
+            "UPLOAD" => HsDescAction::Upload,
+            "RECEIVED" => HsDescAction::Received,
+            "UPLOADED" => HsDescAction::Uploaded,
+            "IGNORE" => HsDescAction::Ignore,
+            "FAILED" => HsDescAction::Failed,
+            "CREATED" => HsDescAction::Created,
+            _ => HsDescAction::Unknown(s.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HsDescReason {
+    BadDesc,
+    QueryRejected,
+    UploadRejected,
+    NotFound,
+    QueryNoHsDir,
+    QueryRateLimited,
+    Unexpected,
+    Unknown(String),
+}
+
+impl From<&str> for HsDescReason {
+    fn from(s: &str) -> Self {
+        match s {
+            "BAD_DESC" => HsDescReason::BadDesc,
+            "QUERY_REJECTED" => HsDescReason::QueryRejected,
+            "UPLOAD_REJECTED" => HsDescReason::UploadRejected,
+            "NOT_FOUND" => HsDescReason::NotFound,
+            "QUERY_NO_HSDIR" => HsDescReason::QueryNoHsDir,
+            "QUERY_RATE_LIMITED" => HsDescReason::QueryRateLimited,
+            "UNEXPECTED" => HsDescReason::Unexpected,
+            _ => HsDescReason::Unknown(s.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for HsDescReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HsDescReason::BadDesc => write!(f, "BAD_DESC"),
+            HsDescReason::QueryRejected => write!(f, "QUERY_REJECTED"),
+            HsDescReason::UploadRejected => write!(f, "UPLOAD_REJECTED"),
+            HsDescReason::NotFound => write!(f, "NOT_FOUND"),
+            HsDescReason::QueryNoHsDir => write!(f, "QUERY_NO_HSDIR"),
+            HsDescReason::QueryRateLimited => write!(f, "QUERY_RATE_LIMITED"),
+            HsDescReason::Unexpected => write!(f, "UNEXPECTED"),
+            HsDescReason::Unknown(ref reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+// Onion service descriptor fetched or uploaded (HS_DESC event)
+#[derive(Debug, Clone)]
+pub struct HsDescEvent {
+    pub action: HsDescAction,
+    // Onion address without ".onion", "UNKNOWN" when tor doesn't know it
+    pub address: String,
+    pub auth_type: String,
+    // HSDir as "$fingerprint~nickname", "UNKNOWN" when tor doesn't know it
+    pub hs_dir: String,
+    pub descriptor_id: Option<String>,
+    pub reason: Option<HsDescReason>,
+}
+
+impl HsDescEvent {
+    pub fn parse(event: &Event) -> Result<HsDescEvent, Error> {
+        check_keyword(event, "HS_DESC")?;
+        let args = ReplyArgs::parse(event.args())?;
+        let positional = args.positional();
+        if positional.len() < 4 {
+            return Err(Error::ParseReply(ParseReplyError::MissingField));
+        }
+        Ok(HsDescEvent {
+            action: HsDescAction::from(positional[0].as_str()),
+            address: positional[1].clone(),
+            auth_type: positional[2].clone(),
+            hs_dir: positional[3].clone(),
+            descriptor_id: args.arg(4).map(|id| id.to_string()),
+            reason: args.value("REASON").map(HsDescReason::from),
+        })
+    }
+}
+
+// Onion service descriptor content (HS_DESC_CONTENT event)
+#[derive(Debug, Clone)]
+pub struct HsDescContentEvent {
+    pub address: String,
+    pub descriptor_id: String,
+    pub hs_dir: String,
+    // Empty when the fetch failed
+    pub descriptor: String,
+}
+
+impl HsDescContentEvent {
+    pub fn parse(event: &Event) -> Result<HsDescContentEvent, Error> {
+        check_keyword(event, "HS_DESC_CONTENT")?;
+        let args = ReplyArgs::parse(event.args())?;
+        let positional = args.positional();
+        if positional.len() < 3 {
+            return Err(Error::ParseReply(ParseReplyError::MissingField));
+        }
+        Ok(HsDescContentEvent {
+            address: positional[0].clone(),
+            descriptor_id: positional[1].clone(),
+            hs_dir: positional[2].clone(),
+            descriptor: event.lines
+                .first()
+                .and_then(|line| line.data.clone())
+                .unwrap_or_default(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use control::{CircuitId, CircuitReason, Event, StreamId};
+    use control::test::event;
+    use super::{AddrMapEvent, CircEvent, CircStatus, HsDescAction, HsDescContentEvent,
+                HsDescEvent, HsDescReason, StreamEvent, StreamStatus};
+
+    #[test]
+    fn test_parse_circ_event() {
+        let circ = CircEvent::parse(&event("CIRC 7 BUILT \
+                                            $AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA~relay1,\
+                                            $BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBB~relay2 \
+                                            BUILD_FLAGS=NEED_CAPACITY PURPOSE=CONTROLLER \
+                                            TIME_CREATED=2017-06-12T14:03:21.123456"))
+            .unwrap();
+        assert_eq!(circ.circ_id, CircuitId::from("7"));
+        assert_eq!(circ.status, CircStatus::Built);
+        assert_eq!(circ.path,
+                   vec!["$AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA~relay1",
+                        "$BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBB~relay2"]);
+        assert_eq!(circ.purpose, Some("CONTROLLER".to_string()));
+        assert_eq!(circ.reason, None);
+
+        let circ = CircEvent::parse(&event("CIRC 8 FAILED PURPOSE=GENERAL REASON=TIMEOUT"))
+            .unwrap();
+        assert_eq!(circ.status, CircStatus::Failed);
+        assert!(circ.path.is_empty());
+        assert_eq!(circ.reason, Some(CircuitReason::Timeout));
+    }
+
+    #[test]
+    fn test_parse_stream_event() {
+        let stream = CircEvent::parse(&event("STREAM 12 NEW 0 example.com:443 \
+                                              SOURCE_ADDR=127.0.0.1:40232 PURPOSE=USER"));
+        assert!(stream.is_err());
+        let stream = StreamEvent::parse(&event("STREAM 12 NEW 0 example.com:443 \
+                                                SOURCE_ADDR=127.0.0.1:40232 PURPOSE=USER"))
+            .unwrap();
+        assert_eq!(stream.stream_id, StreamId::from("12"));
+        assert_eq!(stream.status, StreamStatus::New);
+        assert_eq!(stream.circ_id, CircuitId::from("0"));
+        assert_eq!(stream.target, "example.com:443");
+        assert_eq!(stream.source_addr, Some("127.0.0.1:40232".to_string()));
+        assert_eq!(stream.purpose, Some("USER".to_string()));
+    }
+
+    #[test]
+    fn test_parse_addrmap_event() {
+        let addrmap = AddrMapEvent::parse(&event("ADDRMAP example.com 93.184.216.34 \
+                                                  \"2017-06-12 16:30:00\" \
+                                                  EXPIRES=\"2017-06-12 14:30:00\" CACHED=\"NO\" \
+                                                  STREAMID=42"))
+            .unwrap();
+        assert_eq!(addrmap.address, "example.com");
+        assert_eq!(addrmap.new_address, Some("93.184.216.34".to_string()));
+        assert_eq!(addrmap.expiry, Some("2017-06-12 16:30:00".to_string()));
+        assert_eq!(addrmap.expires, Some("2017-06-12 14:30:00".to_string()));
+        assert_eq!(addrmap.cached, Some(false));
+        assert_eq!(addrmap.stream_id, Some(StreamId::from("42")));
+
+        let addrmap = AddrMapEvent::parse(&event("ADDRMAP nonexistent.example <error> \
+                                                  \"2017-06-12 16:30:00\" error=yes \
+                                                  EXPIRES=\"2017-06-12 14:30:00\" \
+                                                  CACHED=\"NO\""))
+            .unwrap();
+        assert_eq!(addrmap.new_address, None);
+        assert_eq!(addrmap.error, Some("yes".to_string()));
+    }
+
+    #[test]
+    fn test_parse_hs_desc_events() {
+        let address = "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd";
+        let hs_dir = "$AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA";
+        let hs_desc = HsDescEvent::parse(&event(&format!("HS_DESC FAILED {} NO_AUTH {} \
+                                                          REASON=NOT_FOUND",
+                                                         address,
+                                                         hs_dir)))
+            .unwrap();
+        assert_eq!(hs_desc.action, HsDescAction::Failed);
+        assert_eq!(hs_desc.address, address);
+        assert_eq!(hs_desc.descriptor_id, None);
+        assert_eq!(hs_desc.reason, Some(HsDescReason::NotFound));
+
+        let mut content = event(&format!("HS_DESC_CONTENT {} \
+                                          k2vp6ldqt3ilgsyc5fmqdgkpgidcwhnmhrkbuxc5glaqvxkzw3ma {}",
+                                         address,
+                                         hs_dir));
+        content.lines[0].data = Some("hs-descriptor 3\n".to_string());
+        let content = HsDescContentEvent::parse(&content).unwrap();
+        assert_eq!(content.address, address);
+        assert_eq!(content.descriptor, "hs-descriptor 3\n");
+
+        // Events are public, nothing guarantees they have a line
+        let empty = Event { keyword: "HS_DESC_CONTENT".to_string(), lines: vec![] };
+        assert!(HsDescContentEvent::parse(&empty).is_err());
+    }
+}
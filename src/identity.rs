@@ -0,0 +1,140 @@
+use std::io::{Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+use regex::Regex;
+
+use control::{Controller, Error, ParseReplyError, ReadTimeout, Signal};
+
+// Tor does not switch to clean circuits more than once in this interval: a NEWNYM signal received
+// too early is delayed until the interval has passed.
+pub const NEWNYM_INTERVAL: Duration = Duration::from_secs(10);
+
+// Keeps track of the NEWNYM signals sent to tor, so that we know when the requested identity is
+// actually in use.
+pub struct IdentityManager {
+    last_newnym: Option<Instant>,
+    available_at: Option<Instant>,
+    notice_timeout: Duration,
+}
+
+impl Default for IdentityManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IdentityManager {
+    pub fn new() -> Self {
+        IdentityManager {
+            last_newnym: None,
+            available_at: None,
+            notice_timeout: Duration::from_millis(100),
+        }
+    }
+
+    // Time to wait after sending NEWNYM for the rate limiting notice from tor.  Tor reports the
+    // delay it applies, which also accounts for NEWNYM signals sent by other controllers.  A
+    // zero timeout disables the wait, relying only on our own bookkeeping.
+    pub fn notice_timeout(&mut self, notice_timeout: Duration) -> &mut Self {
+        self.notice_timeout = notice_timeout;
+        self
+    }
+
+    pub fn last_newnym(&self) -> Option<Instant> {
+        self.last_newnym
+    }
+
+    // Time left until the identity requested by the last NEWNYM is in use.
+    pub fn newnym_wait(&self) -> Duration {
+        match self.available_at {
+            Some(available_at) => {
+                let now = Instant::now();
+                if available_at > now {
+                    available_at - now
+                } else {
+                    Duration::from_secs(0)
+                }
+            }
+            None => Duration::from_secs(0),
+        }
+    }
+
+    pub fn is_newnym_available(&self) -> bool {
+        self.newnym_wait() == Duration::from_secs(0)
+    }
+
+    // Sends a NEWNYM signal, returning the time left until the new identity is in use.
+    pub fn newnym<T>(&mut self, controller: &mut Controller<T>) -> Result<Duration, Error>
+        where T: Read + Write + ReadTimeout
+    {
+        controller.add_events(&["NOTICE"])?;
+        let res = self.signal_newnym(controller);
+        controller.remove_events(&["NOTICE"])?;
+        res
+    }
+
+    // Sends a NEWNYM signal and blocks until the new identity is in use.
+    pub fn newnym_wait_available<T>(&mut self, controller: &mut Controller<T>) -> Result<(), Error>
+        where T: Read + Write + ReadTimeout
+    {
+        let wait = self.newnym(controller)?;
+        thread::sleep(wait);
+        Ok(())
+    }
+
+    fn signal_newnym<T>(&mut self, controller: &mut Controller<T>) -> Result<Duration, Error>
+        where T: Read + Write + ReadTimeout
+    {
+        let re_rate_limit = Regex::new("^Rate limiting NEWNYM request: \
+                                        delaying by (?P<delay>[0-9]+) second")?;
+        let now = Instant::now();
+        controller.cmd_signal(Signal::Newnym)?;
+
+        // A signal sent while another one is still delayed is merged with it by tor.
+        let mut wait = match self.last_newnym {
+            Some(last) if last > now => last - now,
+            Some(last) if now - last < NEWNYM_INTERVAL => NEWNYM_INTERVAL - (now - last),
+            _ => Duration::from_secs(0),
+        };
+        if self.notice_timeout > Duration::from_secs(0) {
+            let notice = controller.wait_event_matching(self.notice_timeout, |event| {
+                    event.keyword == "NOTICE" && re_rate_limit.is_match(event.args())
+                })?;
+            if let Some(notice) = notice {
+                let delay = re_rate_limit.captures(notice.args())
+                    .and_then(|cap| cap.name("delay"))
+                    .ok_or(Error::ParseReply(ParseReplyError::MissingField))?
+                    .as_str()
+                    .parse::<u64>()
+                    .map_err(|err| Error::ParseReply(ParseReplyError::ParseIntError(err)))?;
+                wait = Duration::from_secs(delay);
+            }
+        }
+
+        // A delayed signal takes effect once the wait is over, which is what the next rate limit
+        // interval counts from.
+        self.last_newnym = Some(now + wait);
+        self.available_at = Some(now + wait);
+        Ok(wait)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use control::test::mock_controller;
+    use super::IdentityManager;
+
+    #[test]
+    fn test_newnym_rate_limit_notice() {
+        let (mut controller, stream) =
+            mock_controller(&["250 OK\r\n",
+                              "250 OK\r\n650 NOTICE Rate limiting NEWNYM request: delaying by 8 \
+                               second(s)\r\n",
+                              "250 OK\r\n"]);
+        let mut identity = IdentityManager::new();
+        assert_eq!(identity.newnym(&mut controller).unwrap(), Duration::from_secs(8));
+        assert!(!identity.is_newnym_available());
+        assert_eq!(stream.sent(), ["SETEVENTS NOTICE", "SIGNAL NEWNYM", "SETEVENTS"]);
+    }
+}
@@ -13,4 +13,9 @@ pub mod utils;
 
 pub mod platform;
 pub mod control;
+pub mod events;
 pub mod process;
+pub mod identity;
+pub mod circuit;
+pub mod stream;
+pub mod onion;
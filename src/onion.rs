@@ -0,0 +1,1033 @@
+use std::fmt;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use crypto::curve25519::{curve25519_base, ge_scalarmult_base};
+use crypto::digest::Digest;
+use crypto::sha2::Sha512;
+use crypto::sha3::Sha3;
+use rand::Rng;
+
+use control::{AddOnion, CommandBuilder, Controller, Error, IntroDosDefense,
+              OnionClientAuthCredential, OnionClientAuthV3, OnionError, OnionFlags, OnionKey,
+              OnionTarget, ParseReplyError, PowDefense, ServiceID};
+
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+// Lowercase base32 (RFC 4648) without padding, as used by onion addresses.
+pub(crate) fn base32_encode(data: &[u8]) -> String {
+    let mut res = String::new();
+    let mut buffer = 0u16;
+    let mut bits = 0;
+    for &byte in data {
+        buffer = (buffer << 8) | u16::from(byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            res.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        res.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    res
+}
+
+// Decodes base32 without padding, in any case.  Returns None on invalid characters.
+pub(crate) fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut res = Vec::new();
+    let mut buffer = 0u16;
+    let mut bits = 0;
+    for c in s.bytes() {
+        let c = c.to_ascii_lowercase();
+        let value = BASE32_ALPHABET.iter().position(|&a| a == c)? as u16;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            res.push((buffer >> bits) as u8);
+        }
+    }
+    Some(res)
+}
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
+                                     abcdefghijklmnopqrstuvwxyz\
+                                     0123456789+/";
+
+// Standard base64 with padding, as used by tor for key blobs.
+pub(crate) fn base64_encode(data: &[u8]) -> String {
+    let mut res = String::new();
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                res.push(BASE64_ALPHABET[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                res.push('=');
+            }
+        }
+    }
+    res
+}
+
+// Decodes standard base64, with or without padding.  Returns None on invalid characters.
+pub(crate) fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let mut res = Vec::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in s.trim_end_matches('=').bytes() {
+        let value = BASE64_ALPHABET.iter().position(|&a| a == c)? as u32;
+        buffer = (buffer << 6) | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            res.push((buffer >> bits) as u8);
+        }
+    }
+    Some(res)
+}
+
+// Files of a HiddenServiceDir
+pub const SECRET_KEY_FILE: &str = "hs_ed25519_secret_key";
+pub const PUBLIC_KEY_FILE: &str = "hs_ed25519_public_key";
+pub const HOSTNAME_FILE: &str = "hostname";
+
+// Key files start with a 32 bytes header, the tag padded with zeros.
+const SECRET_KEY_TAG: &[u8] = b"== ed25519v1-secret: type0 ==";
+const PUBLIC_KEY_TAG: &[u8] = b"== ed25519v1-public: type0 ==";
+const KEY_FILE_HEADER_LEN: usize = 32;
+
+fn read_key_file(path: &Path, tag: &[u8], key_len: usize) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::new();
+    fs::File::open(path)?.read_to_end(&mut data)?;
+    if data.len() != KEY_FILE_HEADER_LEN + key_len || !data.starts_with(tag) ||
+       data[tag.len()..KEY_FILE_HEADER_LEN].iter().any(|&b| b != 0) {
+        return Err(Error::Onion(OnionError::InvalidKeyFile));
+    }
+    Ok(data[KEY_FILE_HEADER_LEN..].to_vec())
+}
+
+fn write_key_file(path: &Path, tag: &[u8], key: &[u8]) -> Result<(), Error> {
+    let mut data = tag.to_vec();
+    data.resize(KEY_FILE_HEADER_LEN, 0);
+    data.extend_from_slice(key);
+    write_private_file(path, &data)
+}
+
+// Writes a file only readable by its owner, as tor requires for the HiddenServiceDir contents.
+// The mode given to open only applies to new files, an existing one is restricted before the
+// data is written.
+fn write_private_file(path: &Path, data: &[u8]) -> Result<(), Error> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(data)?;
+    Ok(())
+}
+
+// Removes the ".onion" suffix of an address, if present.  Only one is removed, so that
+// "<id>.onion.onion" stays invalid.
+pub(crate) fn strip_onion_suffix(address: &str) -> &str {
+    address.strip_suffix(".onion").unwrap_or(address)
+}
+
+// Version 3 onion address, derived from the ed25519 public key of the service:
+//
+//   onion_address = base32(PUBKEY | CHECKSUM | VERSION) + ".onion"
+//   CHECKSUM = SHA3_256(".onion checksum" | PUBKEY | VERSION)[:2]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct OnionAddress {
+    public_key: [u8; 32],
+}
+
+const ONION_ADDRESS_VERSION: u8 = 3;
+const ONION_ADDRESS_LEN: usize = 56;
+
+fn onion_checksum(public_key: &[u8; 32], version: u8) -> [u8; 2] {
+    let mut sha3 = Sha3::sha3_256();
+    sha3.input(b".onion checksum");
+    sha3.input(public_key);
+    sha3.input(&[version]);
+    let mut digest = [0u8; 32];
+    sha3.result(&mut digest);
+    [digest[0], digest[1]]
+}
+
+impl OnionAddress {
+    pub fn from_public_key(public_key: &[u8; 32]) -> Self {
+        OnionAddress { public_key: *public_key }
+    }
+
+    pub fn public_key(&self) -> &[u8; 32] {
+        &self.public_key
+    }
+
+    // Address without the ".onion" suffix, as used in the control protocol.
+    pub fn service_id(&self) -> ServiceID {
+        ServiceID::wrap(self.encode())
+    }
+
+    // Reads a hs_ed25519_public_key file.
+    pub fn read_public_key_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let key = read_key_file(path.as_ref(), PUBLIC_KEY_TAG, 32)?;
+        let mut public_key = [0u8; 32];
+        public_key.copy_from_slice(&key);
+        Ok(OnionAddress { public_key })
+    }
+
+    pub fn write_public_key_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        write_key_file(path.as_ref(), PUBLIC_KEY_TAG, &self.public_key)
+    }
+
+    // Reads a hostname file, containing the address followed by a newline.
+    pub fn read_hostname_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let mut hostname = String::new();
+        fs::File::open(path)?.read_to_string(&mut hostname)?;
+        hostname.trim_end().parse::<OnionAddress>().map_err(Error::Onion)
+    }
+
+    pub fn write_hostname_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        write_private_file(path.as_ref(), format!("{}\n", self).as_bytes())
+    }
+
+    fn encode(&self) -> String {
+        let mut data = Vec::with_capacity(35);
+        data.extend_from_slice(&self.public_key);
+        data.extend_from_slice(&onion_checksum(&self.public_key, ONION_ADDRESS_VERSION));
+        data.push(ONION_ADDRESS_VERSION);
+        base32_encode(&data)
+    }
+}
+
+impl fmt::Display for OnionAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.onion", self.encode())
+    }
+}
+
+// Parses an address with or without the ".onion" suffix, verifying its checksum.
+impl FromStr for OnionAddress {
+    type Err = OnionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let address = strip_onion_suffix(s);
+        if address.len() != ONION_ADDRESS_LEN {
+            return Err(OnionError::InvalidAddressLength(address.len()));
+        }
+        let data = base32_decode(address).ok_or(OnionError::InvalidBase32)?;
+        let version = data[34];
+        if version != ONION_ADDRESS_VERSION {
+            return Err(OnionError::InvalidVersion(version));
+        }
+        let mut public_key = [0u8; 32];
+        public_key.copy_from_slice(&data[..32]);
+        if data[32..34] != onion_checksum(&public_key, version) {
+            return Err(OnionError::InvalidChecksum);
+        }
+        Ok(OnionAddress { public_key })
+    }
+}
+
+// ed25519 keypair of a v3 onion service.
+//
+// Tor stores the secret key in its expanded form: the SHA512 of the 32 bytes seed, with the first
+// half clamped to be used as the secret scalar.  This is also the form used in ADD_ONION key
+// blobs.
+#[derive(Clone)]
+pub struct OnionKeypair {
+    expanded_secret_key: [u8; 64],
+    public_key: [u8; 32],
+}
+
+impl OnionKeypair {
+    // Generates a new random keypair.
+    pub fn generate() -> Self {
+        let seed = rand::thread_rng().gen::<[u8; 32]>();
+        Self::from_seed(&seed)
+    }
+
+    pub fn from_seed(seed: &[u8; 32]) -> Self {
+        let mut expanded_secret_key = [0u8; 64];
+        let mut sha512 = Sha512::new();
+        sha512.input(seed);
+        sha512.result(&mut expanded_secret_key);
+        expanded_secret_key[0] &= 248;
+        expanded_secret_key[31] &= 63;
+        expanded_secret_key[31] |= 64;
+        Self::from_expanded_secret_key(&expanded_secret_key)
+    }
+
+    pub fn from_expanded_secret_key(expanded_secret_key: &[u8; 64]) -> Self {
+        let public_key = ge_scalarmult_base(&expanded_secret_key[..32]).to_bytes();
+        OnionKeypair {
+            expanded_secret_key: *expanded_secret_key,
+            public_key,
+        }
+    }
+
+    pub fn expanded_secret_key(&self) -> &[u8; 64] {
+        &self.expanded_secret_key
+    }
+
+    pub fn public_key(&self) -> &[u8; 32] {
+        &self.public_key
+    }
+
+    pub fn address(&self) -> OnionAddress {
+        OnionAddress::from_public_key(&self.public_key)
+    }
+
+    pub fn service_id(&self) -> ServiceID {
+        self.address().service_id()
+    }
+
+    // Key to create the service with cmd_add_onion, "ED25519-V3:<base64 expanded key>".
+    pub fn onion_key(&self) -> OnionKey {
+        OnionKey::Ed25519V3(base64_encode(&self.expanded_secret_key))
+    }
+
+    // Keypair of an ED25519-V3 key, such as the one returned by cmd_add_onion.
+    pub fn from_onion_key(key: &OnionKey) -> Result<Self, Error> {
+        let blob = match *key {
+            OnionKey::Ed25519V3(ref blob) => blob,
+            _ => return Err(Error::Onion(OnionError::InvalidKeyBlob)),
+        };
+        match base64_decode(blob) {
+            Some(ref expanded_secret_key) if expanded_secret_key.len() == 64 => {
+                let mut key = [0u8; 64];
+                key.copy_from_slice(expanded_secret_key);
+                Ok(Self::from_expanded_secret_key(&key))
+            }
+            _ => Err(Error::Onion(OnionError::InvalidKeyBlob)),
+        }
+    }
+
+    // Reads a hs_ed25519_secret_key file.
+    pub fn read_secret_key_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let key = read_key_file(path.as_ref(), SECRET_KEY_TAG, 64)?;
+        let mut expanded_secret_key = [0u8; 64];
+        expanded_secret_key.copy_from_slice(&key);
+        Ok(Self::from_expanded_secret_key(&expanded_secret_key))
+    }
+
+    pub fn write_secret_key_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        write_key_file(path.as_ref(), SECRET_KEY_TAG, &self.expanded_secret_key)
+    }
+
+    // Reads the keypair from a HiddenServiceDir, checking that it matches the public key and
+    // hostname files when they are present.
+    pub fn read_from_dir<P: AsRef<Path>>(dir: P) -> Result<Self, Error> {
+        let dir = dir.as_ref();
+        let keypair = Self::read_secret_key_file(dir.join(SECRET_KEY_FILE))?;
+        let address = keypair.address();
+        if dir.join(PUBLIC_KEY_FILE).exists() &&
+           OnionAddress::read_public_key_file(dir.join(PUBLIC_KEY_FILE))? != address {
+            return Err(Error::Onion(OnionError::KeyMismatch));
+        }
+        if dir.join(HOSTNAME_FILE).exists() &&
+           OnionAddress::read_hostname_file(dir.join(HOSTNAME_FILE))? != address {
+            return Err(Error::Onion(OnionError::KeyMismatch));
+        }
+        Ok(keypair)
+    }
+
+    // Writes the secret key, public key and hostname files to a HiddenServiceDir, creating it if
+    // needed.  The directory is made only accessible by its owner, as tor requires.
+    pub fn write_to_dir<P: AsRef<Path>>(&self, dir: P) -> Result<(), Error> {
+        let dir = dir.as_ref();
+        if !dir.exists() {
+            let mut builder = fs::DirBuilder::new();
+            builder.recursive(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::DirBuilderExt;
+                builder.mode(0o700);
+            }
+            builder.create(dir)?;
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+        }
+        self.write_secret_key_file(dir.join(SECRET_KEY_FILE))?;
+        self.address().write_public_key_file(dir.join(PUBLIC_KEY_FILE))?;
+        self.address().write_hostname_file(dir.join(HOSTNAME_FILE))
+    }
+}
+
+// Don't leak the secret key in logs
+impl fmt::Debug for OnionKeypair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "OnionKeypair({})", self.address())
+    }
+}
+
+// x25519 keypair of a client authorized to access a v3 onion service.
+#[derive(Clone)]
+pub struct ClientAuthKeypair {
+    secret_key: [u8; 32],
+    public_key: [u8; 32],
+}
+
+impl ClientAuthKeypair {
+    // Generates a new random keypair.
+    pub fn generate() -> Self {
+        let secret_key = rand::thread_rng().gen::<[u8; 32]>();
+        Self::from_secret_key(&secret_key)
+    }
+
+    pub fn from_secret_key(secret_key: &[u8; 32]) -> Self {
+        let mut secret_key = *secret_key;
+        secret_key[0] &= 248;
+        secret_key[31] &= 127;
+        secret_key[31] |= 64;
+        ClientAuthKeypair {
+            secret_key,
+            public_key: curve25519_base(&secret_key),
+        }
+    }
+
+    pub fn secret_key(&self) -> &[u8; 32] {
+        &self.secret_key
+    }
+
+    pub fn public_key(&self) -> &[u8; 32] {
+        &self.public_key
+    }
+
+    // Client authorization to pass to cmd_add_onion in client_auths_v3.
+    pub fn client_auth(&self) -> OnionClientAuthV3 {
+        OnionClientAuthV3 { public_key: self.public_key }
+    }
+
+    // Credentials for the client to access the service at address, to be registered in tor with
+    // cmd_onion_client_auth_add.
+    pub fn credential(&self, address: &OnionAddress) -> OnionClientAuthCredential {
+        OnionClientAuthCredential {
+            service_id: address.service_id(),
+            secret_key: self.secret_key,
+            client_name: None,
+            permanent: false,
+        }
+    }
+
+    // Line of the client's "<name>.auth_private" file, in its ClientOnionAuthDir:
+    // "<onion address>:descriptor:x25519:<base32 secret key>"
+    pub fn auth_private_line(&self, address: &OnionAddress) -> String {
+        format!("{}:descriptor:x25519:{}",
+                address.service_id().as_ref(),
+                base32_encode(&self.secret_key))
+    }
+
+    // Line of the service's "authorized_clients/<name>.auth" file, for persistent services:
+    // "descriptor:x25519:<base32 public key>"
+    pub fn authorized_client_line(&self) -> String {
+        format!("descriptor:x25519:{}", base32_encode(&self.public_key))
+    }
+}
+
+impl fmt::Debug for ClientAuthKeypair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ClientAuthKeypair({})", base32_encode(&self.public_key))
+    }
+}
+
+// Ephemeral onion service created by this process.  The handle keeps the private key, so that
+// the same service can be created again on a new control connection when the previous one was
+// lost.  It doesn't hold the controller: dropping the handle deletes the service before the next
+// command sent on its controller, unless it was detached, and remove deletes it right away.
+pub struct OnionService {
+    add_onion: AddOnion,
+    service_id: ServiceID,
+    dropped_onions: Arc<Mutex<Vec<ServiceID>>>,
+    detached: bool,
+}
+
+impl<T: Read + Write> Controller<T> {
+    // Creates an ephemeral onion service managed by the returned handle.  The handle needs the
+    // private key, so the DiscardPK flag is refused.
+    pub fn create_onion_service(&mut self, add_onion: AddOnion) -> Result<OnionService, Error> {
+        OnionService::create(self, add_onion)
+    }
+
+    // Takes over a service detached by a previous run, given its id and the key returned by
+    // OnionService::detach.  The detached service is replaced by one managed by the handle.
+    pub fn reclaim_onion_service(&mut self,
+                                 service_id: &ServiceID,
+                                 add_onion: AddOnion)
+                                 -> Result<OnionService, Error> {
+        if self.get_onions_detached()?.contains(service_id) {
+            self.cmd_del_onion(service_id.clone())?;
+        }
+        let service = OnionService::create(self, add_onion)?;
+        if service.service_id() != service_id {
+            service.remove(self)?;
+            return Err(Error::Onion(OnionError::KeyMismatch));
+        }
+        Ok(service)
+    }
+}
+
+impl OnionService {
+    fn create<T: Read + Write>(controller: &mut Controller<T>,
+                               mut add_onion: AddOnion)
+                               -> Result<OnionService, Error> {
+        if add_onion.flags.contains(&OnionFlags::DiscardPK) {
+            let flag = "DiscardPK, the OnionService needs the private key";
+            return Err(Error::InvalidArgument(flag.to_string()));
+        }
+        let service_id = Self::add(controller, &mut add_onion)?;
+        Ok(OnionService {
+            add_onion,
+            service_id,
+            dropped_onions: controller.dropped_onions.clone(),
+            detached: false,
+        })
+    }
+
+    // Adds the service, replacing a new key or missing client blobs in add_onion with the ones
+    // generated by tor so that the next ADD_ONION creates the same service.
+    fn add<T: Read + Write>(controller: &mut Controller<T>,
+                            add_onion: &mut AddOnion)
+                            -> Result<ServiceID, Error> {
+        let reply = controller.cmd_add_onion(add_onion.clone())?;
+        if let Some(key) = reply.sk {
+            add_onion.key = key;
+        }
+        for (client_name, client_blob) in reply.client_auths {
+            let client_auth = add_onion.client_auths
+                .iter_mut()
+                .find(|client_auth| client_auth.client_name == client_name);
+            if let Some(client_auth) = client_auth {
+                client_auth.client_blob = Some(client_blob);
+            }
+        }
+        Ok(reply.service_id)
+    }
+
+    pub fn service_id(&self) -> &ServiceID {
+        &self.service_id
+    }
+
+    pub fn key(&self) -> &OnionKey {
+        &self.add_onion.key
+    }
+
+    // Creates the service again on a new control connection.  Services without the Detach flag
+    // end with the connection that created them; a detached one may still exist in tor, in which
+    // case it is deleted first, since tor refuses to add a service twice.
+    pub fn reconnect<T>(&mut self, controller: &mut Controller<T>) -> Result<(), Error>
+        where T: Read + Write
+    {
+        if self.add_onion.flags.contains(&OnionFlags::Detach) {
+            controller.del_onion_if_exists(self.service_id.clone())?;
+        }
+        self.service_id = Self::add(controller, &mut self.add_onion)?;
+        self.dropped_onions = controller.dropped_onions.clone();
+        Ok(())
+    }
+
+    // Deletes the service from tor.
+    pub fn remove<T: Read + Write>(mut self, controller: &mut Controller<T>) -> Result<(), Error> {
+        self.detached = true;
+        controller.cmd_del_onion(self.service_id.clone())
+    }
+
+    // Gives up the handle, returning what is needed to take the service over later.  Without
+    // the Detach flag, the service still ends with the control connection.
+    pub fn detach(mut self) -> (ServiceID, OnionKey) {
+        self.detached = true;
+        (self.service_id.clone(), self.add_onion.key.clone())
+    }
+}
+
+impl Drop for OnionService {
+    fn drop(&mut self) {
+        if !self.detached {
+            if let Ok(mut dropped_onions) = self.dropped_onions.lock() {
+                dropped_onions.push(self.service_id.clone());
+            }
+        }
+    }
+}
+
+// Onion service configured with HiddenServiceDir.  Unlike the ephemeral services of ADD_ONION,
+// it survives tor restarts once the configuration is saved.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HiddenServiceConf {
+    pub dir: PathBuf,
+    pub version: Option<u8>,
+    // As in AddOnion
+    pub ports: Vec<(u16, Option<OnionTarget>)>,
+    pub intro_dos_defense: IntroDosDefense,
+    pub pow_defense: PowDefense,
+    // Other options of the service, like HiddenServiceMaxStreams, in configuration order
+    pub options: Vec<(String, String)>,
+}
+
+impl HiddenServiceConf {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        HiddenServiceConf {
+            dir: dir.into(),
+            version: None,
+            ports: vec![],
+            intro_dos_defense: IntroDosDefense::default(),
+            pow_defense: PowDefense::default(),
+            options: vec![],
+        }
+    }
+
+    // The options of the service for SETCONF, starting with the HiddenServiceDir that tor uses
+    // to tell the services apart.
+    fn conf(&self) -> Vec<(String, String)> {
+        let dir = self.dir.to_string_lossy().into_owned();
+        let mut conf = vec![("HiddenServiceDir".to_string(), dir)];
+        if let Some(version) = self.version {
+            conf.push(("HiddenServiceVersion".to_string(), version.to_string()));
+        }
+        for &(virt_port, ref target) in &self.ports {
+            let port = match *target {
+                Some(ref target) => format!("{} {}", virt_port, target),
+                None => virt_port.to_string(),
+            };
+            conf.push(("HiddenServicePort".to_string(), port));
+        }
+        let intro_dos = &self.intro_dos_defense;
+        let pow = &self.pow_defense;
+        // Both defenses are disabled by default
+        let enabled = |enabled: bool| if enabled { Some(1) } else { None };
+        let defenses = [("HiddenServiceEnableIntroDoSDefense", enabled(intro_dos.enabled)),
+                        ("HiddenServiceEnableIntroDoSRatePerSec", intro_dos.rate_per_sec),
+                        ("HiddenServiceEnableIntroDoSBurstPerSec", intro_dos.burst_per_sec),
+                        ("HiddenServicePoWDefensesEnabled", enabled(pow.enabled)),
+                        ("HiddenServicePoWQueueRate", pow.queue_rate),
+                        ("HiddenServicePoWQueueBurst", pow.queue_burst)];
+        for &(key, value) in &defenses {
+            if let Some(value) = value {
+                conf.push((key.to_string(), value.to_string()));
+            }
+        }
+        conf.extend(self.options.iter().cloned());
+        conf
+    }
+
+    // Reads the id of the service from the hostname file that tor writes in its directory, which
+    // requires tor to run on this host and the directory to be readable by this process.  Tor
+    // writes the file once the service is configured, possibly after SETCONF returns.  The
+    // hostname files of v2 services with client authorization have more than the address on
+    // their first line.
+    pub fn read_service_id(&self) -> Result<ServiceID, Error> {
+        let mut hostname = String::new();
+        fs::File::open(self.dir.join(HOSTNAME_FILE))?.read_to_string(&mut hostname)?;
+        let address = hostname.split_whitespace().next().unwrap_or("");
+        strip_onion_suffix(address).parse::<ServiceID>()
+    }
+}
+
+// Parses a HiddenServicePort value: "VIRTPORT [TARGET]".  Targets that can't be parsed are kept
+// as they are, so that setting the services again leaves them untouched.
+fn parse_hidden_service_port(value: &str) -> Result<(u16, Option<OnionTarget>), Error> {
+    let mut parts = value.splitn(2, ' ');
+    let virt_port = parts.next()
+        .unwrap_or("")
+        .parse::<u16>()
+        .map_err(|err| Error::ParseReply(ParseReplyError::ParseIntError(err)))?;
+    let target = parts.next().map(|target| {
+        let target = target.trim();
+        target.parse::<OnionTarget>().unwrap_or_else(|_| OnionTarget::Other(target.to_string()))
+    });
+    Ok((virt_port, target))
+}
+
+fn parse_conf_u32(value: &str) -> Result<u32, Error> {
+    value.parse::<u32>().map_err(|err| Error::ParseReply(ParseReplyError::ParseIntError(err)))
+}
+
+
+impl<T: Read + Write> Controller<T> {
+    // Onion services configured with HiddenServiceDir, in configuration order.
+    pub fn get_hidden_services(&mut self) -> Result<Vec<HiddenServiceConf>, Error> {
+        let mut services: Vec<HiddenServiceConf> = vec![];
+        for (key, value) in self.cmd_getconf_list(&["HiddenServiceOptions"])? {
+            if key == "HiddenServiceDir" {
+                if !value.is_empty() {
+                    services.push(HiddenServiceConf::new(value));
+                }
+                continue;
+            }
+            // Every other option belongs to the last HiddenServiceDir
+            let service = match services.last_mut() {
+                Some(service) => service,
+                None => continue,
+            };
+            match key.as_str() {
+                "HiddenServiceVersion" => {
+                    let version = value.parse::<u8>()
+                        .map_err(|err| Error::ParseReply(ParseReplyError::ParseIntError(err)))?;
+                    service.version = Some(version);
+                }
+                "HiddenServicePort" => service.ports.push(parse_hidden_service_port(&value)?),
+                "HiddenServiceEnableIntroDoSDefense" => {
+                    service.intro_dos_defense.enabled = parse_conf_u32(&value)? != 0;
+                }
+                "HiddenServiceEnableIntroDoSRatePerSec" => {
+                    service.intro_dos_defense.rate_per_sec = Some(parse_conf_u32(&value)?);
+                }
+                "HiddenServiceEnableIntroDoSBurstPerSec" => {
+                    service.intro_dos_defense.burst_per_sec = Some(parse_conf_u32(&value)?);
+                }
+                "HiddenServicePoWDefensesEnabled" => {
+                    service.pow_defense.enabled = parse_conf_u32(&value)? != 0;
+                }
+                "HiddenServicePoWQueueRate" => {
+                    service.pow_defense.queue_rate = Some(parse_conf_u32(&value)?);
+                }
+                "HiddenServicePoWQueueBurst" => {
+                    service.pow_defense.queue_burst = Some(parse_conf_u32(&value)?);
+                }
+                _ => service.options.push((key, value)),
+            }
+        }
+        Ok(services)
+    }
+
+    // Replaces all the onion services configured with HiddenServiceDir.  Tor only accepts the
+    // whole list of services at once, setting some of them would remove the others.
+    pub fn set_hidden_services(&mut self, services: &[HiddenServiceConf]) -> Result<(), Error> {
+        if services.is_empty() {
+            let req = CommandBuilder::new("SETCONF").arg("HiddenServiceDir").build()?;
+            return self.raw_cmd(&req).map(|_| ());
+        }
+        for service in services {
+            service.intro_dos_defense.validate().map_err(Error::Onion)?;
+            service.pow_defense.validate().map_err(Error::Onion)?;
+        }
+        let conf = services.iter().flat_map(|service| service.conf()).collect::<Vec<_>>();
+        let args = conf.iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect::<Vec<_>>();
+        self.cmd_setconf(&args)
+    }
+
+    // Adds an onion service to the configuration, replacing the one using the same directory,
+    // and saves the configuration to the torrc if save is true.  Returns the id of the service
+    // if its hostname file could already be read, see HiddenServiceConf::read_service_id.
+    pub fn add_hidden_service(&mut self,
+                              service: HiddenServiceConf,
+                              save: bool)
+                              -> Result<Option<ServiceID>, Error> {
+        let mut services = self.get_hidden_services()?;
+        match services.iter().position(|other| other.dir == service.dir) {
+            Some(i) => services[i] = service.clone(),
+            None => services.push(service.clone()),
+        }
+        self.set_hidden_services(&services)?;
+        if save {
+            self.cmd_saveconf(false)?;
+        }
+        Ok(service.read_service_id().ok())
+    }
+
+    // Removes the onion service using dir from the configuration, and saves the configuration to
+    // the torrc if save is true.  Returns whether the service was configured.
+    pub fn remove_hidden_service<P: AsRef<Path>>(&mut self,
+                                                 dir: P,
+                                                 save: bool)
+                                                 -> Result<bool, Error> {
+        let mut services = self.get_hidden_services()?;
+        let len = services.len();
+        services.retain(|service| service.dir != dir.as_ref());
+        if services.len() == len {
+            return Ok(false);
+        }
+        self.set_hidden_services(&services)?;
+        if save {
+            self.cmd_saveconf(false)?;
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use super::{base32_decode, base32_encode, base64_decode, base64_encode, OnionAddress,
+                ClientAuthKeypair, HiddenServiceConf, OnionKeypair, HOSTNAME_FILE,
+                SECRET_KEY_FILE, parse_hidden_service_port};
+    use control::{AddOnion, Error, KeyType, OnionError, OnionFlags, OnionKey, OnionTarget,
+                  PowDefense, ServiceID, Signal};
+    use control::test::mock_controller;
+
+    const SERVICE_ID: &str = "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd";
+
+    fn add_onion(key: OnionKey, flags: Vec<OnionFlags>) -> AddOnion {
+        AddOnion {
+            key,
+            flags,
+            max_streams: None,
+            ports: vec![(80, None)],
+            client_auths: vec![],
+            client_auths_v3: vec![],
+            pow_defense: PowDefense::default(),
+        }
+    }
+
+    #[test]
+    fn test_base32() {
+        assert_eq!(base32_encode(b""), "");
+        assert_eq!(base32_encode(b"f"), "my");
+        assert_eq!(base32_encode(b"foobar"), "mzxw6ytboi");
+        assert_eq!(base32_decode("MZXW6YTBOI"), Some(b"foobar".to_vec()));
+        assert_eq!(base32_decode("mzxw6ytb0i"), None);
+    }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64_decode("Zm8="), Some(b"fo".to_vec()));
+        assert_eq!(base64_decode("Zm9vYmFy"), Some(b"foobar".to_vec()));
+        assert_eq!(base64_decode("Zm9v-mFy"), None);
+    }
+
+    #[test]
+    fn test_onion_address() {
+        let public_key = [0xd7, 0x5a, 0x98, 0x01, 0x82, 0xb1, 0x0a, 0xb7, 0xd5, 0x4b, 0xfe, 0xd3,
+                          0xc9, 0x64, 0x07, 0x3a, 0x0e, 0xe1, 0x72, 0xf3, 0xda, 0xa6, 0x23, 0x25,
+                          0xaf, 0x02, 0x1a, 0x68, 0xf7, 0x07, 0x51, 0x1a];
+        let address = OnionAddress::from_public_key(&public_key);
+        assert_eq!(address.to_string(),
+                   "25njqamcweflpvkl73j4szahhihoc4xt3ktcgjnpaingr5yhkenl5sid.onion");
+        assert_eq!(address.service_id().as_ref(),
+                   "25njqamcweflpvkl73j4szahhihoc4xt3ktcgjnpaingr5yhkenl5sid");
+        assert_eq!("25njqamcweflpvkl73j4szahhihoc4xt3ktcgjnpaingr5yhkenl5sid"
+                       .parse::<OnionAddress>()
+                       .unwrap(),
+                   address);
+
+        assert!("2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion"
+            .parse::<OnionAddress>()
+            .is_ok());
+        match "3gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion"
+            .parse::<OnionAddress>() {
+            Err(OnionError::InvalidChecksum) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+        match "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wi.onion"
+            .parse::<OnionAddress>() {
+            Err(OnionError::InvalidAddressLength(55)) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+        match "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion.onion"
+            .parse::<OnionAddress>() {
+            Err(OnionError::InvalidAddressLength(62)) => (),
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_onion_keypair() {
+        // Test vector 1 from RFC 8032
+        let seed = [0x9d, 0x61, 0xb1, 0x9d, 0xef, 0xfd, 0x5a, 0x60, 0xba, 0x84, 0x4a, 0xf4, 0x92,
+                    0xec, 0x2c, 0xc4, 0x44, 0x49, 0xc5, 0x69, 0x7b, 0x32, 0x69, 0x19, 0x70, 0x3b,
+                    0xac, 0x03, 0x1c, 0xae, 0x7f, 0x60];
+        let keypair = OnionKeypair::from_seed(&seed);
+        assert_eq!(keypair.address().to_string(),
+                   "25njqamcweflpvkl73j4szahhihoc4xt3ktcgjnpaingr5yhkenl5sid.onion");
+        match keypair.onion_key() {
+            OnionKey::Ed25519V3(blob) => assert_eq!(blob.len(), 88),
+            key => panic!("unexpected key {:?}", key),
+        }
+
+        let keypair = OnionKeypair::generate();
+        let expanded = OnionKeypair::from_expanded_secret_key(keypair.expanded_secret_key());
+        assert_eq!(expanded.address(), keypair.address());
+    }
+
+    #[test]
+    fn test_onion_key_files() {
+        let keypair = OnionKeypair::generate();
+        let from_blob = OnionKeypair::from_onion_key(&keypair.onion_key()).unwrap();
+        assert_eq!(from_blob.expanded_secret_key()[..], keypair.expanded_secret_key()[..]);
+
+        let dir = env::temp_dir().join(format!("tor_controller_test_{}", keypair.service_id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(SECRET_KEY_FILE), b"").unwrap();
+        keypair.write_to_dir(&dir).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(dir.join(SECRET_KEY_FILE)), 0o600);
+            assert_eq!(mode(dir.clone()), 0o700);
+        }
+        let secret_key = fs::read(dir.join(SECRET_KEY_FILE)).unwrap();
+        assert_eq!(secret_key.len(), 96);
+        assert!(secret_key.starts_with(b"== ed25519v1-secret: type0 ==\0\0\0"));
+        let hostname = fs::read_to_string(dir.join(HOSTNAME_FILE)).unwrap();
+        assert_eq!(hostname, format!("{}\n", keypair.address()));
+        let read = OnionKeypair::read_from_dir(&dir).unwrap();
+        assert_eq!(read.address(), keypair.address());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_client_auth_keypair() {
+        // Alice's keys from RFC 7748
+        let secret_key = [0x77, 0x07, 0x6d, 0x0a, 0x73, 0x18, 0xa5, 0x7d, 0x3c, 0x16, 0xc1, 0x72,
+                          0x51, 0xb2, 0x66, 0x45, 0xdf, 0x4c, 0x2f, 0x87, 0xeb, 0xc0, 0x99, 0x2a,
+                          0xb1, 0x77, 0xfb, 0xa5, 0x1d, 0xb9, 0x2c, 0x2a];
+        let keypair = ClientAuthKeypair::from_secret_key(&secret_key);
+        assert_eq!(keypair.authorized_client_line(),
+                   format!("descriptor:x25519:{}",
+                           base32_encode(&[0x85, 0x20, 0xf0, 0x09, 0x89, 0x30, 0xa7, 0x54,
+                                           0x74, 0x8b, 0x7d, 0xdc, 0xb4, 0x3e, 0xf7, 0x5a,
+                                           0x0d, 0xbf, 0x3a, 0x0d, 0x26, 0x38, 0x1a, 0xf4,
+                                           0xeb, 0xa4, 0xa9, 0x8e, 0xaa, 0x9b, 0x4e, 0x6a])));
+        assert_eq!(keypair.client_auth().public_key, *keypair.public_key());
+
+        let address: OnionAddress = "25njqamcweflpvkl73j4szahhihoc4xt3ktcgjnpaingr5yhkenl5sid"
+            .parse()
+            .unwrap();
+        let line = keypair.auth_private_line(&address);
+        assert!(line.starts_with("25njqamcweflpvkl73j4szahhihoc4xt3ktcgjnpaingr5yhkenl5sid:\
+                                  descriptor:x25519:"));
+        assert_eq!(base32_decode(line.rsplit(':').next().unwrap()).unwrap()[..],
+                   keypair.secret_key()[..]);
+    }
+
+    #[test]
+    fn test_hidden_service_conf() {
+        let mut service = HiddenServiceConf::new("/var/lib/tor/web");
+        service.version = Some(3);
+        service.ports = vec![(80, Some(OnionTarget::Addr("127.0.0.1:8080".parse().unwrap()))),
+                             (443, None),
+                             (22, Some(OnionTarget::Unix(PathBuf::from("/run/ssh.sock"))))];
+        service.pow_defense = PowDefense {
+            enabled: true,
+            queue_rate: None,
+            queue_burst: Some(500),
+        };
+        service.options = vec![("HiddenServiceMaxStreams".to_string(), "10".to_string())];
+        let conf = service.conf();
+        let conf = conf.iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(conf,
+                   vec![("HiddenServiceDir", "/var/lib/tor/web"),
+                        ("HiddenServiceVersion", "3"),
+                        ("HiddenServicePort", "80 127.0.0.1:8080"),
+                        ("HiddenServicePort", "443"),
+                        ("HiddenServicePort", "22 unix:/run/ssh.sock"),
+                        ("HiddenServicePoWDefensesEnabled", "1"),
+                        ("HiddenServicePoWQueueBurst", "500"),
+                        ("HiddenServiceMaxStreams", "10")]);
+        for (i, &(_, value)) in conf[2..5].iter().enumerate() {
+            assert_eq!(parse_hidden_service_port(value).unwrap(), service.ports[i]);
+        }
+
+        // Ports read from tor are written back as they were
+        let values = ["80 127.0.0.1", "80 localhost", "80 localhost:8080", "80 [::1]",
+                      "22 unix:\"/run/my app.sock\"", "80 not a target"];
+        let mut service = HiddenServiceConf::new("/var/lib/tor/other");
+        for value in values.iter() {
+            service.ports.push(parse_hidden_service_port(value).unwrap());
+        }
+        assert_eq!(service.ports[1], (80, Some(OnionTarget::Host("localhost".to_string(), None))));
+        assert_eq!(service.ports[4],
+                   (22, Some(OnionTarget::Unix(PathBuf::from("/run/my app.sock")))));
+        assert_eq!(service.ports[5], (80, Some(OnionTarget::Other("not a target".to_string()))));
+        let conf = service.conf();
+        let ports = conf[1..].iter().map(|(_, value)| value.as_str()).collect::<Vec<_>>();
+        assert_eq!(ports, values);
+    }
+
+    #[test]
+    fn test_onion_service_drop() {
+        let added = |service_id: &str| {
+            format!("250-ServiceID={}\r\n250-PrivateKey=ED25519-V3:c2stMQ==\r\n250 OK\r\n",
+                    service_id)
+        };
+        let (mut controller, stream) =
+            mock_controller(&[&added("sid1"), "250 OK\r\n", "250 OK\r\n", &added("sid2"),
+                              "250 OK\r\n"]);
+        let new_key = OnionKey::New(KeyType::Ed25519V3);
+        let service = controller.create_onion_service(add_onion(new_key.clone(), vec![]))
+            .unwrap();
+        assert_eq!(service.service_id().as_ref(), "sid1");
+        assert_eq!(*service.key(), OnionKey::Ed25519V3("c2stMQ==".to_string()));
+        drop(service);
+        controller.cmd_signal(Signal::Newnym).unwrap();
+
+        let service = controller.create_onion_service(add_onion(new_key, vec![OnionFlags::Detach]))
+            .unwrap();
+        let (service_id, _) = service.detach();
+        assert_eq!(service_id.as_ref(), "sid2");
+        controller.cmd_signal(Signal::Newnym).unwrap();
+        assert_eq!(stream.sent(),
+                   ["ADD_ONION NEW:ED25519-V3 Port=80",
+                    "DEL_ONION sid1",
+                    "SIGNAL NEWNYM",
+                    "ADD_ONION NEW:ED25519-V3 Flags=Detach Port=80",
+                    "SIGNAL NEWNYM"]);
+    }
+
+    #[test]
+    fn test_onion_service_reconnect() {
+        let (mut controller, _) =
+            mock_controller(&["250-ServiceID=sid1\r\n250-PrivateKey=ED25519-V3:c2stMQ==\r\n\
+                               250 OK\r\n"]);
+        let new_key = OnionKey::New(KeyType::Ed25519V3);
+        let add = add_onion(new_key, vec![OnionFlags::Detach]);
+        let mut service = controller.create_onion_service(add).unwrap();
+
+        // The service is gone with tor, which doesn't know it anymore
+        let (mut controller, stream) =
+            mock_controller(&["552 Unknown Onion Service id\r\n",
+                              "250-ServiceID=sid1\r\n250 OK\r\n"]);
+        service.reconnect(&mut controller).unwrap();
+        assert_eq!(stream.sent(),
+                   ["DEL_ONION sid1", "ADD_ONION ED25519-V3:c2stMQ== Flags=Detach Port=80"]);
+
+        let (mut controller, _) = mock_controller(&["551 Internal error\r\n"]);
+        assert!(service.reconnect(&mut controller).is_err());
+        service.detach();
+    }
+
+    #[test]
+    fn test_reclaim_onion_service() {
+        let (mut controller, stream) =
+            mock_controller(&["551 No onion services of the specified type.\r\n",
+                              "250-ServiceID=sid2\r\n250 OK\r\n",
+                              "250 OK\r\n"]);
+        let service_id = SERVICE_ID.parse::<ServiceID>().unwrap();
+        let key = OnionKey::Ed25519V3("c2stMQ==".to_string());
+        match controller.reclaim_onion_service(&service_id, add_onion(key, vec![])) {
+            Err(Error::Onion(OnionError::KeyMismatch)) => (),
+            res => panic!("unexpected result {:?}", res.map(|service| service.detach())),
+        }
+        assert_eq!(stream.sent(),
+                   ["GETINFO onions/detached", "ADD_ONION ED25519-V3:c2stMQ== Port=80",
+                    "DEL_ONION sid2"]);
+    }
+}
@@ -3,6 +3,7 @@
 mod unix {
     use control::Controller;
     use control::Connection;
+    use control::ReadTimeout;
 
     use std::path::Path;
     use std::io;
@@ -10,10 +11,11 @@ mod unix {
     use std::io::BufWriter;
     use std::net::Shutdown;
     use std::os::unix::net::UnixStream;
+    use std::time::Duration;
 
     impl Controller<UnixStream> {
         pub fn from_socket_file<P: AsRef<Path>>(path: P) -> Result<Controller<UnixStream>, io::Error> {
-            Ok(Controller { con: Connection::<UnixStream>::connect(path)? })
+            Ok(Controller::new(Connection::<UnixStream>::connect(path)?))
         }
 
         pub fn close(&mut self) -> Result<(), io::Error> {
@@ -21,6 +23,12 @@ mod unix {
         }
     }
 
+    impl ReadTimeout for UnixStream {
+        fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+            UnixStream::set_read_timeout(self, dur)
+        }
+    }
+
     impl Connection<UnixStream> {
         fn connect<P: AsRef<Path>>(path: P) -> Result<Connection<UnixStream>, io::Error> {
             let raw_stream = UnixStream::connect(path)?;
@@ -2,6 +2,8 @@ extern crate regex;
 extern crate timer;
 extern crate chrono;
 
+use std::error;
+use std::fmt;
 use std::io;
 use std::thread;
 use std::process::{Command, Stdio, Child, ChildStdout};
@@ -20,6 +22,30 @@ pub enum Error {
     Timeout,
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Process(ref err) => write!(f, "tor process error: {}", err),
+            Error::Tor(ref line, _) => write!(f, "tor failed: {}", line),
+            Error::InvalidLogLine => write!(f, "invalid tor log line"),
+            Error::InvalidBootstrapLine(ref line) => write!(f, "invalid bootstrap line: {}", line),
+            Error::Regex(ref err) => write!(f, "invalid regex: {}", err),
+            Error::ProcessNotStarted => write!(f, "tor process not started"),
+            Error::Timeout => write!(f, "timed out waiting for tor to bootstrap"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Process(ref err) => Some(err),
+            Error::Regex(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
 pub struct TorProcess {
     tor_cmd: String,
     args: Vec<String>,
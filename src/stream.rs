@@ -0,0 +1,112 @@
+use std::io::{Read, Write};
+use std::time::Duration;
+
+use control::{CircuitId, Controller, Error, ReadTimeout, StreamId};
+use events::StreamEvent;
+
+// Attaches new streams to circuits chosen by a policy instead of letting tor do it.
+//
+// While the attacher is alive, tor leaves new streams unattached (__LeaveStreamsUnattached) and
+// the policy is asked for a circuit for each of them: returning None lets tor choose one.  The
+// previous value of __LeaveStreamsUnattached is restored on drop.
+pub struct StreamAttacher<'a, T: Read + Write + ReadTimeout + 'a, F> {
+    controller: &'a mut Controller<T>,
+    policy: F,
+    leave_streams_unattached: String,
+}
+
+impl<'a, T, F> StreamAttacher<'a, T, F>
+    where T: Read + Write + ReadTimeout,
+          F: FnMut(&StreamEvent) -> Option<CircuitId>
+{
+    pub fn new(controller: &'a mut Controller<T>, policy: F) -> Result<Self, Error> {
+        let conf = controller.cmd_getconf(&["__LeaveStreamsUnattached"])?;
+        let leave_streams_unattached = conf.get("__LeaveStreamsUnattached")
+            .and_then(|values| values.first())
+            .map_or("0".to_string(), |value| value.clone());
+        controller.cmd_setconf(&[("__LeaveStreamsUnattached", "1")])?;
+        if let Err(err) = controller.add_events(&["STREAM"]) {
+            controller.cmd_setconf(&[("__LeaveStreamsUnattached", &leave_streams_unattached)])
+                .unwrap_or(());
+            return Err(err);
+        }
+        Ok(StreamAttacher { controller, policy, leave_streams_unattached })
+    }
+
+    // Gives access to the controller, to send other commands while attaching streams.
+    pub fn controller(&mut self) -> &mut Controller<T> {
+        self.controller
+    }
+
+    // Waits up to timeout for a new stream and attaches it.  Returns the id of the attached
+    // stream, or None if no new stream arrived in time.  Other STREAM events are left in the
+    // controller's queue.
+    pub fn process(&mut self, timeout: Duration) -> Result<Option<StreamId>, Error> {
+        let event = self.controller.wait_event_matching(timeout, |event| {
+                event.keyword == "STREAM" &&
+                matches!(event.args().split(' ').nth(1), Some("NEW") | Some("NEWRESOLVE"))
+            })?;
+        let stream = match event {
+            Some(event) => StreamEvent::parse(&event)?,
+            None => return Ok(None),
+        };
+        let circ_id = (self.policy)(&stream);
+        self.controller.cmd_attachstream(&stream.stream_id, circ_id.as_ref(), None)?;
+        Ok(Some(stream.stream_id))
+    }
+}
+
+impl<'a, T: Read + Write + ReadTimeout + 'a, F> Drop for StreamAttacher<'a, T, F> {
+    fn drop(&mut self) {
+        self.controller.remove_events(&["STREAM"]).unwrap_or(());
+        self.controller
+            .cmd_setconf(&[("__LeaveStreamsUnattached", &self.leave_streams_unattached)])
+            .unwrap_or(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use control::{CircuitId, StreamId};
+    use control::test::mock_controller;
+    use events::StreamStatus;
+    use super::StreamAttacher;
+
+    #[test]
+    fn test_stream_attacher() {
+        let (mut controller, stream) =
+            mock_controller(&["250 __LeaveStreamsUnattached=0\r\n",
+                              "250 OK\r\n",
+                              "250 OK\r\n650 STREAM 1 SUCCEEDED 3 example.com:80\r\n\
+                               650 STREAM 2 NEW 0 example.com:443\r\n\
+                               650 STREAM 3 NEWRESOLVE 0 example.org:0\r\n",
+                              "250 OK\r\n",
+                              "250 OK\r\n",
+                              "250 OK\r\n",
+                              "250 OK\r\n"]);
+        {
+            let mut attacher = StreamAttacher::new(&mut controller, |stream| {
+                    match stream.status {
+                        StreamStatus::New => Some(CircuitId::from("7")),
+                        _ => None,
+                    }
+                })
+                .unwrap();
+            let timeout = Duration::from_millis(10);
+            assert_eq!(attacher.process(timeout).unwrap(), Some(StreamId::from("2")));
+            assert_eq!(attacher.process(timeout).unwrap(), Some(StreamId::from("3")));
+            assert_eq!(attacher.process(timeout).unwrap(), None);
+            assert_eq!(attacher.controller().pop_event().unwrap().args(),
+                       "1 SUCCEEDED 3 example.com:80");
+        }
+        assert_eq!(stream.sent(),
+                   ["GETCONF __LeaveStreamsUnattached",
+                    "SETCONF __LeaveStreamsUnattached=1",
+                    "SETEVENTS STREAM",
+                    "ATTACHSTREAM 2 7",
+                    "ATTACHSTREAM 3 0",
+                    "SETEVENTS",
+                    "SETCONF __LeaveStreamsUnattached=0"]);
+    }
+}
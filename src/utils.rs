@@ -1,4 +1,7 @@
+use std::error;
+use std::fmt;
 use std::io;
+use std::num;
 use std::process::Command;
 use regex;
 use regex::Regex;
@@ -32,6 +35,34 @@ pub enum Error {
     Command(io::Error),
     CommandOutput(string::FromUtf8Error),
     TorVersionTooShort,
+    // A version number doesn't fit in a u16
+    VersionNumber(num::ParseIntError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Regex(ref err) => write!(f, "invalid regex: {}", err),
+            Error::RegexCapture => write!(f, "unexpected tor version format"),
+            Error::MissingField => write!(f, "missing field in tor version"),
+            Error::Command(ref err) => write!(f, "failed to run tor: {}", err),
+            Error::CommandOutput(ref err) => write!(f, "invalid tor output: {}", err),
+            Error::TorVersionTooShort => write!(f, "tor version too short"),
+            Error::VersionNumber(ref err) => write!(f, "invalid tor version number: {}", err),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Regex(ref err) => Some(err),
+            Error::Command(ref err) => Some(err),
+            Error::CommandOutput(ref err) => Some(err),
+            Error::VersionNumber(ref err) => Some(err),
+            _ => None,
+        }
+    }
 }
 
 pub fn get_system_tor_version(tor_cmd: Option<&str>) -> Result<TorVersion, Error> {
@@ -70,13 +101,14 @@ pub fn parse_tor_version(tor_version_str: &str) -> Result<TorVersion, Error> {
     let status_tag = ver_cap.name("status_tag").map( |m| m.as_str() );
     let extra_info = ver_cap.name("extra_info").map( |m| m.as_str() );
 
-    // At this point the parse should always be sucessfull becuse the regex limit the captured
-    // strings to be integer numbers for major, minor, micro and patch_level.
+    // The regex limits the captured strings to be integer numbers for major, minor, micro and
+    // patch_level, but they can still be too large.
+    let parse = |number: &str| number.parse::<u16>().map_err(Error::VersionNumber);
     Ok(TorVersion {
-        major: major.parse::<u16>().unwrap(),
-        minor: minor.parse::<u16>().unwrap(),
-        micro: micro.parse::<u16>().unwrap(),
-        patch_level: patch_level.parse::<u16>().unwrap(),
+        major: parse(major)?,
+        minor: parse(minor)?,
+        micro: parse(micro)?,
+        patch_level: parse(patch_level)?,
         status_tag: status_tag.map(|s| s[1..].to_string()),
         extra_info: extra_info.map(|s| s[2..s.len() - 1].to_string()),
     })
@@ -133,5 +165,6 @@ mod test {
                        status_tag: None,
                        extra_info: None,
                    });
+        assert!(parse_tor_version("0.4.99999.1").is_err());
     }
 }